@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse};
+use serde::Deserialize;
+
+use crate::config::Secret;
+use crate::limits::{self, RateLimiter};
+use crate::ErrorResponse;
+
+/// Keys shorter than this are too easy to guess.
+const MIN_KEY_LEN: usize = 16;
+
+/// What an API key may do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `/search` and `/neighbors`, for the upload pipeline.
+    Search,
+    /// `/rebuild`, deletions, `/status` and everything under `/admin`.
+    Admin,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Search => "search",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiKey {
+    /// Who the key belongs to; logged and used to tell clients apart.
+    pub name: String,
+    pub key: Secret,
+    pub scopes: HashSet<Scope>,
+}
+
+/// Registered as app data to require a bearer key on every route but the health,
+/// readiness and metrics endpoints. Without keys searches are open and every
+/// admin endpoint is off.
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+/// The name of the key a request was authenticated with, left in its extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientName(pub String);
+
+impl ApiKeys {
+    /// Parses `API_KEYS`, a JSON array of `{"name", "key", "scopes"}` objects.
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let keys: Vec<ApiKey> = serde_json::from_str(json)
+            .map_err(|e| format!("API_KEYS must be a JSON array of keys: {}", e))?;
+        Self::new(keys)
+    }
+
+    pub fn new(keys: Vec<ApiKey>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut names = HashSet::new();
+        let mut secrets = HashSet::new();
+        for key in keys.iter() {
+            if key.name.trim().is_empty() || !names.insert(key.name.as_str()) {
+                return Err(
+                    format!("API key names must be unique and non-empty: '{}'", key.name).into(),
+                );
+            }
+            if key.key.expose().len() < MIN_KEY_LEN {
+                return Err(format!(
+                    "API key '{}' is shorter than {} characters",
+                    key.name, MIN_KEY_LEN
+                )
+                .into());
+            }
+            if !secrets.insert(key.key.expose()) {
+                return Err(format!("API key '{}' reuses another key's secret", key.name).into());
+            }
+            if key.scopes.is_empty() {
+                return Err(format!("API key '{}' has no scopes", key.name).into());
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The key whose secret is `token`. Every key is compared, so the time taken
+    /// does not depend on which one matched.
+    fn find(&self, token: &str) -> Option<&ApiKey> {
+        self.keys
+            .iter()
+            .fold(None, |found, key| match key.key.matches(token) {
+                true => Some(key),
+                false => found,
+            })
+    }
+}
+
+/// Lets searches through when no keys are configured, so a local or test setup
+/// works without any.
+pub async fn require_search(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    authorize(Scope::Search, req, next).await
+}
+
+/// Refuses everything when no keys are configured: rebuilds, deletions and
+/// configuration changes must never be open to whoever can reach the service.
+pub async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    authorize(Scope::Admin, req, next).await
+}
+
+/// Passes the request on if it carries a key with `scope`, answering 401 for a
+/// missing or unknown key and 403 for a key without the scope. Missing and
+/// unknown keys use up the address's rate limit. With no keys configured only
+/// searches pass.
+async fn authorize<B: MessageBody>(
+    scope: Scope,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let keys = match req.app_data::<web::Data<ApiKeys>>() {
+        Some(keys) if !keys.is_empty() => keys.clone(),
+        _ if scope == Scope::Search => return Ok(next.call(req).await?.map_into_left_body()),
+        _ => {
+            let response = HttpResponse::Forbidden().json(ErrorResponse {
+                error: "This endpoint is disabled; configure API_KEYS to enable it".to_string(),
+            });
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let key = match token.and_then(|token| keys.find(token)) {
+        Some(key) => key,
+        None => {
+            // Rate limiting runs after this, so key guesses are limited here by address
+            if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+                if let Err(wait) = limiter.acquire(&limiter.client(&req)) {
+                    let response = limits::rate_limited(wait);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(ErrorResponse {
+                    error: "Missing or invalid API key".to_string(),
+                });
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    if !key.scopes.contains(&scope) {
+        log::warn!(
+            "API key '{}' was refused {} {}: it lacks the {} scope",
+            key.name,
+            req.method(),
+            req.path(),
+            scope.as_str()
+        );
+        let response = HttpResponse::Forbidden().json(ErrorResponse {
+            error: format!("API key '{}' lacks the {} scope", key.name, scope.as_str()),
+        });
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    req.extensions_mut().insert(ClientName(key.name.clone()));
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_parsed_and_validated() {
+        let keys = ApiKeys::from_json(
+            r#"[
+                {"name": "upload-pipeline", "key": "search-key-0123456789", "scopes": ["search"]},
+                {"name": "ops", "key": "admin-key-0123456789", "scopes": ["search", "admin"]}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            keys.find("search-key-0123456789").unwrap().name,
+            "upload-pipeline"
+        );
+        assert!(keys
+            .find("admin-key-0123456789")
+            .unwrap()
+            .scopes
+            .contains(&Scope::Admin));
+        assert!(keys.find("search-key").is_none());
+        assert!(!format!("{:?}", keys).contains("0123456789"));
+
+        for json in [
+            r#"{"name": "ops"}"#,
+            r#"[{"name": "ops", "key": "short", "scopes": ["admin"]}]"#,
+            r#"[{"name": "ops", "key": "admin-key-0123456789", "scopes": []}]"#,
+            r#"[{"name": "ops", "key": "admin-key-0123456789", "scopes": ["root"]}]"#,
+            r#"[{"name": "a", "key": "admin-key-0123456789", "scopes": ["admin"]},
+                {"name": "b", "key": "admin-key-0123456789", "scopes": ["search"]}]"#,
+            r#"[{"name": "a", "key": "admin-key-0123456789", "scopes": ["admin"]},
+                {"name": "a", "key": "search-key-0123456789", "scopes": ["search"]}]"#,
+        ] {
+            assert!(ApiKeys::from_json(json).is_err(), "{} was accepted", json);
+        }
+    }
+}
@@ -1,75 +1,586 @@
-use std::env;
 use std::error::Error;
+use std::time::{Duration, Instant};
 
-use google_cloud_bigquery::client::google_cloud_auth::credentials::CredentialsFile;
-use google_cloud_bigquery::client::{Client, ClientConfig};
-use google_cloud_bigquery::http::job::query::QueryRequest;
-use google_cloud_bigquery::http::tabledata::list::Value;
+use serde::{Deserialize, Serialize};
 
+use crate::bigquery_api::{
+    self, BigQueryClient, GetQueryResultsRequest, QueryParameter, QueryParameterType,
+    QueryParameterValue, QueryRequest, RestClient, Tuple, Value, DEFAULT_API_URL,
+    DEFAULT_REQUEST_TIMEOUT, QUERY_SCOPES,
+};
+use crate::config::Secret;
+use crate::retry::{self, RetryPolicy};
 use crate::videohash::VideoHash;
+use crate::writeback::parse_var;
+
+/// Rows requested per page of query results.
+const PAGE_SIZE: i64 = 50_000;
+/// How long each request waits server-side for the query job to complete.
+const POLL_TIMEOUT_MS: i64 = 10_000;
+/// Give up on a query job that has not completed after this long.
+const JOB_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Where video hashes are read from, and which rows count.
+///
+/// Built from the settings with `from_lookup`, which validates everything up front
+/// so a bad table name or filter fails at startup instead of on the first query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BigQuerySource {
+    /// REST endpoint, normally the public one.
+    pub api_url: String,
+    /// Project the query jobs run (and are billed) in.
+    pub project_id: Option<String>,
+    /// Fully qualified `project.dataset.table` or `dataset.table`.
+    pub table: String,
+    pub id_column: String,
+    pub hash_column: String,
+    pub created_at_column: String,
+    /// Extra SQL condition rows must satisfy, e.g. `NOT is_deleted`. Values belong
+    /// in `filter_parameters` and are referenced as `@name`.
+    pub filter: Option<String>,
+    pub filter_parameters: Vec<FilterParameter>,
+    /// Retries for each API call that fails with a network error, 429 or 5xx.
+    pub retry: RetryPolicy,
+    pub request_timeout: Duration,
+    /// Service account key from `GOOGLE_SA_KEY`; without one the default
+    /// credentials are used.
+    pub credentials: Option<Secret>,
+}
 
-pub async fn fetch_video_hashes() -> Result<Vec<(String, VideoHash)>, Box<dyn Error + Send + Sync>>
-{
-    let (client, project_id) = create_bigquery_client().await?;
+/// A named query parameter referenced from `BigQuerySource::filter`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterParameter {
+    pub name: String,
+    /// BigQuery type name: `STRING`, `INT64`, `FLOAT64` or `BOOL`.
+    pub parameter_type: String,
+    pub value: String,
+}
 
-    let query_sql = r#"
-        SELECT video_id, videohash 
-        FROM `hot-or-not-feed-intelligence.yral_ds.video_unique`
-        ORDER BY created_at DESC
-    "#;
+/// Parameter names the incremental query reserves for itself.
+const RESERVED_PARAMETER_PREFIX: &str = "watermark_";
+
+impl Default for BigQuerySource {
+    fn default() -> Self {
+        Self {
+            api_url: DEFAULT_API_URL.to_string(),
+            project_id: None,
+            table: "hot-or-not-feed-intelligence.yral_ds.video_unique".to_string(),
+            id_column: "video_id".to_string(),
+            hash_column: "videohash".to_string(),
+            created_at_column: "created_at".to_string(),
+            filter: None,
+            filter_parameters: Vec::new(),
+            retry: RetryPolicy::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            credentials: None,
+        }
+    }
+}
 
-    log::info!("Executing BigQuery query to fetch video hashes");
+impl BigQuerySource {
+    /// Reads the source from `BIGQUERY_*` settings, falling back to
+    /// `GOOGLE_CLOUD_PROJECT` for the project and to the defaults for the rest.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let defaults = Self::default();
+        let filter_parameters = match lookup("BIGQUERY_FILTER_PARAMS") {
+            Some(json) => parse_filter_parameters(&json)?,
+            None => Vec::new(),
+        };
+
+        let source = Self {
+            api_url: lookup("BIGQUERY_API_URL").unwrap_or(defaults.api_url),
+            project_id: lookup("BIGQUERY_PROJECT").or_else(|| lookup("GOOGLE_CLOUD_PROJECT")),
+            table: lookup("BIGQUERY_TABLE").unwrap_or(defaults.table),
+            id_column: lookup("BIGQUERY_ID_COLUMN").unwrap_or(defaults.id_column),
+            hash_column: lookup("BIGQUERY_HASH_COLUMN").unwrap_or(defaults.hash_column),
+            created_at_column: lookup("BIGQUERY_CREATED_AT_COLUMN")
+                .unwrap_or(defaults.created_at_column),
+            filter: lookup("BIGQUERY_FILTER").filter(|filter| !filter.trim().is_empty()),
+            filter_parameters,
+            retry: RetryPolicy {
+                max_attempts: parse_var(
+                    &lookup,
+                    "BIGQUERY_MAX_ATTEMPTS",
+                    defaults.retry.max_attempts,
+                )?,
+                initial_backoff: Duration::from_millis(parse_var(
+                    &lookup,
+                    "BIGQUERY_RETRY_INITIAL_MS",
+                    defaults.retry.initial_backoff.as_millis() as u64,
+                )?),
+                max_backoff: Duration::from_millis(parse_var(
+                    &lookup,
+                    "BIGQUERY_RETRY_MAX_MS",
+                    defaults.retry.max_backoff.as_millis() as u64,
+                )?),
+            },
+            request_timeout: Duration::from_secs(parse_var(
+                &lookup,
+                "BIGQUERY_REQUEST_TIMEOUT_SECS",
+                defaults.request_timeout.as_secs(),
+            )?),
+            credentials: lookup("GOOGLE_SA_KEY").map(Secret::new),
+        };
+        source.validate()?;
+        Ok(source)
+    }
 
-    let request = QueryRequest {
-        query: query_sql.to_string(),
-        use_legacy_sql: false,
-        ..Default::default()
-    };
+    /// Checks that every name is a plain identifier and that the filter and its
+    /// parameters agree with each other.
+    pub fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.api_url.starts_with("https://") && !self.api_url.starts_with("http://") {
+            return Err(format!("Invalid BigQuery API URL '{}'", self.api_url).into());
+        }
+        if self.retry.max_attempts == 0 {
+            return Err("BigQuery calls need at least one attempt".into());
+        }
+        if self.request_timeout <= Duration::from_millis(POLL_TIMEOUT_MS as u64) {
+            return Err(format!(
+                "BigQuery request timeout must be longer than the {}ms BigQuery is asked to wait for a query",
+                POLL_TIMEOUT_MS
+            )
+            .into());
+        }
+
+        if let Some(project_id) = &self.project_id {
+            if project_id.is_empty() || !project_id.chars().all(is_project_char) {
+                return Err(format!("Invalid BigQuery project id '{}'", project_id).into());
+            }
+        }
+
+        let parts: Vec<&str> = self.table.split('.').collect();
+        let valid_table = match parts.as_slice() {
+            [project, dataset, table] => {
+                !project.is_empty()
+                    && project.chars().all(is_project_char)
+                    && is_identifier(dataset)
+                    && is_identifier(table)
+            }
+            [dataset, table] => is_identifier(dataset) && is_identifier(table),
+            _ => false,
+        };
+        if !valid_table {
+            return Err(format!(
+                "Invalid BigQuery table '{}', expected project.dataset.table or dataset.table",
+                self.table
+            )
+            .into());
+        }
+
+        for (setting, column) in [
+            ("id column", &self.id_column),
+            ("hash column", &self.hash_column),
+            ("created_at column", &self.created_at_column),
+        ] {
+            if !is_identifier(column) {
+                return Err(format!("Invalid BigQuery {} '{}'", setting, column).into());
+            }
+        }
+
+        let referenced = match &self.filter {
+            Some(filter) => {
+                if filter.contains(';') || filter.contains("--") || filter.contains("/*") {
+                    return Err(format!(
+                        "BigQuery filter must be a single condition without ';' or comments: {}",
+                        filter
+                    )
+                    .into());
+                }
+                referenced_parameters(filter)
+            }
+            None => Vec::new(),
+        };
+
+        for parameter in self.filter_parameters.iter() {
+            if !is_identifier(&parameter.name)
+                || parameter.name.starts_with(RESERVED_PARAMETER_PREFIX)
+            {
+                return Err(format!(
+                    "Invalid BigQuery filter parameter name '{}'",
+                    parameter.name
+                )
+                .into());
+            }
+            if !referenced.contains(&parameter.name) {
+                return Err(format!(
+                    "BigQuery filter parameter '{}' is not used by the filter",
+                    parameter.name
+                )
+                .into());
+            }
+        }
+        for name in referenced.iter() {
+            if !self.filter_parameters.iter().any(|p| &p.name == name) {
+                return Err(format!(
+                    "BigQuery filter references @{} but no value was configured for it",
+                    name
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A REST client for `api_url` using the environment's credentials.
+    pub async fn connect(&self) -> Result<RestClient, Box<dyn Error + Send + Sync>> {
+        let auth = bigquery_api::token_source(&QUERY_SCOPES, self.credentials.as_ref()).await?;
+        Ok(RestClient::new(&self.api_url, auth)
+            .with_retry_policy(self.retry.clone())
+            .with_request_timeout(self.request_timeout))
+    }
+
+    fn project_id(&self) -> Result<&str, Box<dyn Error + Send + Sync>> {
+        self.project_id.as_deref().ok_or_else(|| {
+            "No BigQuery project configured; set BIGQUERY_PROJECT or GOOGLE_CLOUD_PROJECT".into()
+        })
+    }
+}
+
+/// Parses `BIGQUERY_FILTER_PARAMS`, a JSON object of parameter names to scalar values.
+fn parse_filter_parameters(
+    json: &str,
+) -> Result<Vec<FilterParameter>, Box<dyn Error + Send + Sync>> {
+    let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)
+        .map_err(|e| format!("BIGQUERY_FILTER_PARAMS must be a JSON object: {}", e))?;
+
+    values
+        .into_iter()
+        .map(|(name, value)| {
+            let (parameter_type, value) = match value {
+                serde_json::Value::String(value) => ("STRING", value),
+                serde_json::Value::Bool(value) => ("BOOL", value.to_string()),
+                serde_json::Value::Number(value) if value.is_i64() => ("INT64", value.to_string()),
+                serde_json::Value::Number(value) => ("FLOAT64", value.to_string()),
+                other => {
+                    return Err(format!(
+                    "BigQuery filter parameter '{}' must be a string, number or boolean, got {}",
+                    name, other
+                )
+                    .into())
+                }
+            };
+            Ok(FilterParameter {
+                name,
+                parameter_type: parameter_type.to_string(),
+                value,
+            })
+        })
+        .collect()
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_project_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == ':' || c == '.'
+}
+
+/// Names of the `@parameters` a filter refers to.
+fn referenced_parameters(filter: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = filter;
+    while let Some(at) = rest.find('@') {
+        let name: String = rest[at + 1..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+            .collect();
+        rest = &rest[at + 1 + name.len()..];
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Position in the table ordered by `created_at`, with `video_id` breaking ties
+/// between rows created in the same microsecond.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Watermark {
+    pub created_at_micros: i64,
+    pub video_id: String,
+}
+
+/// What a `stream_video_hashes` call loaded.
+#[derive(Debug, Default)]
+pub struct LoadSummary {
+    /// Hashes passed to the page callback.
+    pub loaded: usize,
+    /// Newest row seen, or `None` if no row had a `created_at`.
+    pub watermark: Option<Watermark>,
+}
+
+pub async fn fetch_video_hashes(
+    client: &dyn BigQueryClient,
+    source: &BigQuerySource,
+) -> Result<Vec<(String, VideoHash)>, Box<dyn Error + Send + Sync>> {
+    let mut results = Vec::new();
+    stream_video_hashes(client, source, None, |page| {
+        results.extend(page);
+        Ok(())
+    })
+    .await?;
+
+    Ok(results)
+}
+
+/// Runs the video hash query and hands each page of parsed rows to `on_page` as it
+/// arrives, so callers never need the whole table in one response.
+///
+/// With `since`, only rows after that watermark are fetched, oldest first.
+/// Waits for the query job to complete, then pages through the results with
+/// `getQueryResults`. Fails if the number of rows received does not match the
+/// job's `total_rows`.
+pub async fn stream_video_hashes<F>(
+    client: &dyn BigQueryClient,
+    source: &BigQuerySource,
+    since: Option<&Watermark>,
+    mut on_page: F,
+) -> Result<LoadSummary, Box<dyn Error + Send + Sync>>
+where
+    F: FnMut(Vec<(String, VideoHash)>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let project_id = source.project_id()?;
+
+    match since {
+        Some(watermark) => log::info!(
+            "Executing BigQuery query to fetch video hashes created after {:?}",
+            watermark
+        ),
+        None => log::info!("Executing BigQuery query to fetch video hashes"),
+    }
+
+    let request = build_query(source, since);
 
     let query_response = client
-        .job()
-        .query(&project_id, &request)
+        .query(project_id, &request)
         .await
         .map_err(|e| format!("Failed to execute BigQuery query: {}", e))?;
 
-    let row_count = query_response.rows.as_ref().map_or(0, |rows| rows.len());
+    if let Some(errors) = query_response.errors.as_ref().filter(|e| !e.is_empty()) {
+        return Err(format!("BigQuery query failed: {:?}", errors).into());
+    }
+
+    let job_id = query_response.job_reference.job_id.clone();
+    let location = query_response.job_reference.location.clone();
+    let started = Instant::now();
+
+    let mut job_complete = query_response.job_complete;
+    let mut total_rows = query_response.total_rows;
+    let mut page_token = query_response.page_token;
+    let mut rows_received = 0;
+    let mut summary = LoadSummary::default();
+
+    if job_complete {
+        let rows = query_response.rows.unwrap_or_default();
+        rows_received += rows.len();
+        deliver_page(rows, &mut on_page, &mut summary)?;
+    }
+
+    while !job_complete || page_token.is_some() {
+        if !job_complete && started.elapsed() > JOB_TIMEOUT {
+            return Err(format!(
+                "BigQuery job {} did not complete within {:?}",
+                job_id, JOB_TIMEOUT
+            )
+            .into());
+        }
+
+        let request = GetQueryResultsRequest {
+            page_token: page_token.clone(),
+            max_results: Some(PAGE_SIZE),
+            timeout_ms: Some(POLL_TIMEOUT_MS),
+            location: location.clone(),
+        };
+        let response = client
+            .get_query_results(project_id, &job_id, &request)
+            .await
+            .map_err(|e| format!("Failed to fetch BigQuery query results: {}", e))?;
+
+        if !response.job_complete {
+            log::info!("Waiting for BigQuery job {} to complete", job_id);
+            continue;
+        }
+
+        job_complete = true;
+        total_rows = response.total_rows;
+        page_token = response.page_token;
+
+        let rows = response.rows.unwrap_or_default();
+        rows_received += rows.len();
+        deliver_page(rows, &mut on_page, &mut summary)?;
+        log::info!(
+            "Fetched {} of {} BigQuery rows",
+            rows_received,
+            total_rows.unwrap_or(0)
+        );
+    }
+
+    let total_rows = total_rows.unwrap_or(0);
+    if rows_received as i64 != total_rows {
+        return Err(format!(
+            "BigQuery returned {} rows but the query reported {}; refusing a partial load",
+            rows_received, total_rows
+        )
+        .into());
+    }
+
+    log::info!("Loaded {} video hashes from BigQuery", summary.loaded);
+    Ok(summary)
+}
+
+/// The full-table query, or only the rows after `since` in watermark order.
+/// Every value goes in as a query parameter; only validated identifiers and the
+/// configured filter are spliced into the SQL.
+fn build_query(source: &BigQuerySource, since: Option<&Watermark>) -> QueryRequest {
+    let id = &source.id_column;
+    let created_at = &source.created_at_column;
+
+    let mut conditions = Vec::new();
+    let mut query_parameters: Vec<QueryParameter> = source
+        .filter_parameters
+        .iter()
+        .map(|p| query_parameter(&p.name, &p.parameter_type, p.value.clone()))
+        .collect();
+    if let Some(filter) = &source.filter {
+        conditions.push(format!("({})", filter));
+    }
+    if let Some(watermark) = since {
+        conditions.push(format!(
+            "(`{created_at}` > TIMESTAMP_MICROS(@watermark_micros) \
+             OR (`{created_at}` = TIMESTAMP_MICROS(@watermark_micros) AND `{id}` > @watermark_video_id))"
+        ));
+        query_parameters.push(query_parameter(
+            "watermark_micros",
+            "INT64",
+            watermark.created_at_micros.to_string(),
+        ));
+        query_parameters.push(query_parameter(
+            "watermark_video_id",
+            "STRING",
+            watermark.video_id.clone(),
+        ));
+    }
 
-    log::info!(
-        "BigQuery response: query successful, returned {} rows",
-        row_count
+    let mut query = format!(
+        "SELECT `{id}` AS video_id, `{hash}` AS videohash, \
+         UNIX_MICROS(`{created_at}`) AS created_at_micros FROM `{table}`",
+        hash = source.hash_column,
+        table = source.table,
     );
+    if !conditions.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(&conditions.join(" AND "));
+    }
+    query.push_str(&match since {
+        Some(_) => format!(" ORDER BY `{created_at}`, `{id}`"),
+        None => format!(" ORDER BY `{created_at}` DESC"),
+    });
+
+    QueryRequest {
+        request_id: Some(request_id()),
+        query,
+        use_legacy_sql: false,
+        parameter_mode: Some("NAMED".to_string()),
+        query_parameters,
+        max_results: Some(PAGE_SIZE),
+        timeout_ms: Some(POLL_TIMEOUT_MS),
+    }
+}
 
-    let mut results = Vec::new();
+/// A random UUID-shaped id, the format BigQuery recommends for `requestId`.
+fn request_id() -> String {
+    let hex = format!("{:016x}{:016x}", retry::random_u64(), retry::random_u64());
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
 
-    if let Some(rows) = query_response.rows {
-        for row in rows {
-            let f = &row.f;
+fn query_parameter(name: &str, parameter_type: &str, value: String) -> QueryParameter {
+    QueryParameter {
+        name: Some(name.to_string()),
+        parameter_type: QueryParameterType {
+            parameter_type: parameter_type.to_string(),
+        },
+        parameter_value: QueryParameterValue { value: Some(value) },
+    }
+}
 
-            if f.len() >= 2 {
-                let video_id = match extract_string_from_value(&f[0].v) {
-                    Some(id) => id,
-                    None => continue,
-                };
+/// Parses a page of rows, passes it on and folds it into `summary`.
+fn deliver_page<F>(
+    rows: Vec<Tuple>,
+    on_page: &mut F,
+    summary: &mut LoadSummary,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    F: FnMut(Vec<(String, VideoHash)>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let (page, watermark) = parse_rows(rows);
+    summary.loaded += page.len();
+    if watermark > summary.watermark {
+        summary.watermark = watermark;
+    }
+    on_page(page)
+}
 
-                let hash_string = match extract_string_from_value(&f[1].v) {
-                    Some(hash) => hash,
-                    None => continue,
+/// Extracts `(video_id, videohash)` pairs, skipping rows that are null or malformed,
+/// along with the newest watermark among them. Malformed rows still advance the
+/// watermark so they are not fetched again on every sync.
+fn parse_rows(rows: Vec<Tuple>) -> (Vec<(String, VideoHash)>, Option<Watermark>) {
+    let mut results = Vec::with_capacity(rows.len());
+    let mut newest: Option<Watermark> = None;
+
+    for row in rows {
+        let f = &row.f;
+
+        if f.len() >= 2 {
+            let video_id = match extract_string_from_value(&f[0].v) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            // INT64 columns arrive as strings
+            let created_at_micros = f
+                .get(2)
+                .and_then(|cell| extract_string_from_value(&cell.v))
+                .and_then(|micros| micros.parse().ok());
+            if let Some(created_at_micros) = created_at_micros {
+                let watermark = Watermark {
+                    created_at_micros,
+                    video_id: video_id.clone(),
                 };
+                if newest.as_ref() < Some(&watermark) {
+                    newest = Some(watermark);
+                }
+            }
 
-                match VideoHash::from_binary_string(&hash_string) {
-                    Ok(hash) => {
-                        results.push((video_id, hash));
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to parse hash for video_id {}: {}", video_id, e);
-                    }
+            let hash_string = match extract_string_from_value(&f[1].v) {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            match VideoHash::from_binary_string(&hash_string) {
+                Ok(hash) => {
+                    results.push((video_id, hash));
+                }
+                Err(e) => {
+                    log::warn!("Failed to parse hash for video_id {}: {}", video_id, e);
                 }
             }
         }
     }
 
-    log::info!("Loaded {} video hashes from BigQuery", results.len());
-    Ok(results)
+    (results, newest)
 }
 
 fn extract_string_from_value(value: &Value) -> Option<String> {
@@ -79,67 +590,142 @@ fn extract_string_from_value(value: &Value) -> Option<String> {
     }
 }
 
-async fn create_bigquery_client() -> Result<(Client, String), Box<dyn Error + Send + Sync>> {
-    if let Ok(sa_key_json) = env::var("GOOGLE_SA_KEY") {
-        log::info!("Creating BigQuery client with GOOGLE_SA_KEY");
-
-        let cred = CredentialsFile::new_from_str(&sa_key_json)
-            .await
-            .map_err(|e| format!("Failed to parse service account credentials: {}", e))?;
-
-        let project_id = env::var("GOOGLE_CLOUD_PROJECT")
-            .map_err(|_| "GOOGLE_CLOUD_PROJECT environment variable is required")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bigquery_api::Cell;
 
-        let (config, _) = ClientConfig::new_with_credentials(cred)
-            .await
-            .map_err(|e| format!("Failed to create client config with credentials: {}", e))?;
-
-        let client = Client::new(config)
-            .await
-            .map_err(|e| format!("Failed to create BigQuery client: {}", e))?;
+    fn row(values: Vec<Value>) -> Tuple {
+        Tuple {
+            f: values.into_iter().map(|v| Cell { v }).collect(),
+        }
+    }
 
-        return Ok((client, project_id));
+    #[test]
+    fn test_parse_rows_skips_malformed_rows() {
+        let rows = vec![
+            row(vec![
+                Value::String("video-001".to_string()),
+                Value::String("0".repeat(64)),
+            ]),
+            row(vec![Value::String("video-002".to_string()), Value::Null]),
+            row(vec![
+                Value::String("video-003".to_string()),
+                Value::String("not a hash".to_string()),
+            ]),
+            row(vec![Value::String("video-004".to_string())]),
+        ];
+
+        let (parsed, _) = parse_rows(rows);
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].0, "video-001");
+        assert_eq!(parsed[0].1.hash, "0".repeat(64));
     }
 
-    if let Ok(creds_path) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
-        log::info!(
-            "Creating BigQuery client with credentials file: {}",
-            creds_path
+    #[test]
+    fn test_parse_rows_tracks_newest_watermark() {
+        let rows = vec![
+            row(vec![
+                Value::String("video-b".to_string()),
+                Value::String("0".repeat(64)),
+                Value::String("200".to_string()),
+            ]),
+            row(vec![
+                Value::String("video-a".to_string()),
+                Value::String("1".repeat(64)),
+                Value::String("200".to_string()),
+            ]),
+            row(vec![
+                Value::String("video-c".to_string()),
+                Value::String("not a hash".to_string()),
+                Value::String("100".to_string()),
+            ]),
+        ];
+
+        let (parsed, watermark) = parse_rows(rows);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            watermark,
+            Some(Watermark {
+                created_at_micros: 200,
+                video_id: "video-b".to_string(),
+            })
         );
+    }
 
-        let cred = CredentialsFile::new_from_file(creds_path)
-            .await
-            .map_err(|e| format!("Failed to load credentials from file: {}", e))?;
-
-        let project_id = env::var("GOOGLE_CLOUD_PROJECT")
-            .map_err(|_| "GOOGLE_CLOUD_PROJECT environment variable is required")?;
-
-        let (config, _) = ClientConfig::new_with_credentials(cred)
-            .await
-            .map_err(|e| format!("Failed to create client config with credentials: {}", e))?;
-
-        let client = Client::new(config)
-            .await
-            .map_err(|e| format!("Failed to create BigQuery client: {}", e))?;
+    #[test]
+    fn test_incremental_query_uses_parameters() {
+        let request = build_query(
+            &BigQuerySource::default(),
+            Some(&Watermark {
+                created_at_micros: 1_700_000_000_000_000,
+                video_id: "video-'; DROP TABLE x; --".to_string(),
+            }),
+        );
 
-        return Ok((client, project_id));
+        assert!(request.query.contains("@watermark_micros"));
+        assert!(!request.query.contains("DROP TABLE"));
+        assert_eq!(request.parameter_mode.as_deref(), Some("NAMED"));
+        assert_eq!(request.query_parameters.len(), 2);
+        assert_eq!(
+            request.query_parameters[1].parameter_value.value.as_deref(),
+            Some("video-'; DROP TABLE x; --")
+        );
     }
 
-    log::info!("Creating BigQuery client with application default credentials");
-
-    let project_id = env::var("GOOGLE_CLOUD_PROJECT")
-        .map_err(|_| "GOOGLE_CLOUD_PROJECT environment variable is required when using application default credentials")?;
+    fn source_from(vars: &[(&str, &str)]) -> Result<BigQuerySource, Box<dyn Error + Send + Sync>> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        BigQuerySource::from_lookup(|key| vars.get(key).cloned())
+    }
 
-    let (config, _) = ClientConfig::new_with_auth().await.map_err(|e| {
-        format!(
-            "Failed to create client config with application default credentials: {}",
-            e
-        )
-    })?;
+    #[test]
+    fn test_source_from_env() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let source = source_from(&[
+            ("GOOGLE_CLOUD_PROJECT", "staging-project"),
+            ("BIGQUERY_TABLE", "staging_ds.videos"),
+            ("BIGQUERY_HASH_COLUMN", "phash"),
+            ("BIGQUERY_FILTER", "NOT is_deleted AND status != @banned"),
+            ("BIGQUERY_FILTER_PARAMS", r#"{"banned": "banned"}"#),
+        ])?;
+
+        assert_eq!(source.project_id.as_deref(), Some("staging-project"));
+        assert_eq!(source.id_column, "video_id");
+        let request = build_query(&source, None);
+        assert!(request
+            .query
+            .contains("`phash` AS videohash, UNIX_MICROS(`created_at`) AS created_at_micros FROM `staging_ds.videos` WHERE (NOT is_deleted AND status != @banned)"));
+        assert_eq!(request.query_parameters.len(), 1);
+        assert_eq!(
+            request.query_parameters[0].parameter_type.parameter_type,
+            "STRING"
+        );
 
-    let client = Client::new(config)
-        .await
-        .map_err(|e| format!("Failed to create BigQuery client: {}", e))?;
+        assert_eq!(source_from(&[])?, BigQuerySource::default());
+        Ok(())
+    }
 
-    Ok((client, project_id))
+    #[test]
+    fn test_invalid_sources_are_rejected() {
+        for vars in [
+            vec![("BIGQUERY_TABLE", "videos")],
+            vec![("BIGQUERY_TABLE", "ds.videos`; DROP TABLE x")],
+            vec![("BIGQUERY_ID_COLUMN", "video id")],
+            vec![("BIGQUERY_API_URL", "bigquery.local")],
+            vec![("BIGQUERY_MAX_ATTEMPTS", "0")],
+            vec![("BIGQUERY_REQUEST_TIMEOUT_SECS", "5")],
+            vec![("BIGQUERY_FILTER", "status = @status")],
+            vec![("BIGQUERY_FILTER", "TRUE; DELETE FROM x")],
+            vec![("BIGQUERY_FILTER_PARAMS", r#"{"unused": 1}"#)],
+            vec![("BIGQUERY_FILTER_PARAMS", "not json")],
+            vec![
+                ("BIGQUERY_FILTER", "x = @watermark_micros"),
+                ("BIGQUERY_FILTER_PARAMS", r#"{"watermark_micros": 1}"#),
+            ],
+        ] {
+            assert!(source_from(&vars).is_err(), "accepted {:?}", vars);
+        }
+    }
 }
@@ -0,0 +1,374 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use google_cloud_auth::credentials::CredentialsFile;
+use google_cloud_auth::project::Config;
+use google_cloud_auth::token::DefaultTokenSourceProvider;
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::Secret;
+use crate::metrics;
+use crate::retry::{RetryPolicy, SendError};
+
+/// Public BigQuery REST endpoint; overridden in tests to point at a local stand-in.
+pub const DEFAULT_API_URL: &str = "https://bigquery.googleapis.com/bigquery/v2";
+
+/// Running query jobs needs the full BigQuery scope.
+pub const QUERY_SCOPES: [&str; 1] = ["https://www.googleapis.com/auth/bigquery"];
+
+/// Per-request timeout unless configured otherwise; long enough for the server-side
+/// wait of a query plus a full page of results.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Body of `jobs.query`.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    /// Makes a retried request attach to the job the first attempt started instead
+    /// of running the query twice.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub query: String,
+    pub use_legacy_sql: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter_mode: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub query_parameters: Vec<QueryParameter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryParameter {
+    pub name: Option<String>,
+    pub parameter_type: QueryParameterType,
+    pub parameter_value: QueryParameterValue,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryParameterType {
+    #[serde(rename = "type")]
+    pub parameter_type: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryParameterValue {
+    pub value: Option<String>,
+}
+
+/// Query string of `jobs.getQueryResults`.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetQueryResultsRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobReference {
+    pub job_id: String,
+    pub location: Option<String>,
+}
+
+/// What `jobs.query` and `jobs.getQueryResults` both return. `total_rows`,
+/// `page_token` and `rows` are only meaningful once `job_complete` is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryResponse {
+    pub job_reference: JobReference,
+    #[serde(default)]
+    pub job_complete: bool,
+    #[serde(default, deserialize_with = "int64")]
+    pub total_rows: Option<i64>,
+    pub page_token: Option<String>,
+    pub rows: Option<Vec<Tuple>>,
+    pub errors: Option<Vec<ErrorProto>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ErrorProto {
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+/// A result row.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Tuple {
+    pub f: Vec<Cell>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Cell {
+    pub v: Value,
+}
+
+/// A cell value. Scalars of every type, including `INT64`, arrive as strings.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    String(String),
+    Array(Vec<Cell>),
+    Struct(Tuple),
+}
+
+/// The REST API encodes 64-bit integers as JSON strings.
+fn int64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Int64 {
+        String(String),
+        Number(i64),
+    }
+
+    match Option::<Int64>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Int64::Number(n)) => Ok(Some(n)),
+        Some(Int64::String(s)) => s.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// The two calls the hash loader makes. Implemented over HTTP by `RestClient`;
+/// tests point that at a local fake or swap in their own implementation.
+#[async_trait]
+pub trait BigQueryClient: Send + Sync {
+    async fn query(
+        &self,
+        project_id: &str,
+        request: &QueryRequest,
+    ) -> Result<QueryResponse, Box<dyn Error + Send + Sync>>;
+
+    async fn get_query_results(
+        &self,
+        project_id: &str,
+        job_id: &str,
+        request: &GetQueryResultsRequest,
+    ) -> Result<QueryResponse, Box<dyn Error + Send + Sync>>;
+}
+
+/// Talks to the BigQuery REST API at `api_url`, retrying calls that fail for a
+/// transient reason.
+pub struct RestClient {
+    http: reqwest::Client,
+    api_url: String,
+    auth: Arc<dyn TokenSource>,
+    retry: RetryPolicy,
+    request_timeout: Duration,
+}
+
+impl RestClient {
+    pub fn new(api_url: &str, auth: Arc<dyn TokenSource>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            auth,
+            retry: RetryPolicy::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Bounds each HTTP request, including reading the response. Has to exceed the
+    /// time BigQuery is asked to wait for a query job.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sends the request `build` makes until it succeeds, fails for good or runs out
+    /// of attempts. Rejected credentials and other 4xx responses are not retried.
+    async fn send(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+        call: &str,
+    ) -> Result<QueryResponse, Box<dyn Error + Send + Sync>> {
+        let outcomes =
+            |outcome: &str| metrics::BIGQUERY_REQUESTS.with_label_values(&[call, outcome]);
+        let mut attempt = 1;
+        loop {
+            let error = match self.send_once(build(), call).await {
+                Ok(response) => {
+                    outcomes("ok").inc();
+                    return Ok(response);
+                }
+                Err(SendError::Fatal(e)) => {
+                    outcomes("failed").inc();
+                    return Err(e);
+                }
+                Err(SendError::Retryable(e)) => e,
+            };
+            if attempt >= self.retry.max_attempts {
+                outcomes("failed").inc();
+                return Err(format!("{} (gave up after {} attempts)", error, attempt).into());
+            }
+            outcomes("retried").inc();
+
+            let delay = self.retry.backoff(attempt);
+            log::warn!(
+                "{} (attempt {}/{}), retrying in {:?}",
+                error,
+                attempt,
+                self.retry.max_attempts,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(
+        &self,
+        request: reqwest::RequestBuilder,
+        call: &str,
+    ) -> Result<QueryResponse, SendError> {
+        let token = self.auth.token().await.map_err(|e| {
+            SendError::Retryable(format!("Failed to get BigQuery access token: {}", e).into())
+        })?;
+        let response = request
+            .header(reqwest::header::AUTHORIZATION, token)
+            .timeout(self.request_timeout)
+            .send()
+            .await
+            .map_err(|e| {
+                SendError::Retryable(format!("BigQuery {} request failed: {}", call, e).into())
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(
+                if status == reqwest::StatusCode::UNAUTHORIZED
+                    || status == reqwest::StatusCode::FORBIDDEN
+                {
+                    SendError::Fatal(
+                        format!(
+                            "BigQuery rejected the credentials for {} ({}): {}",
+                            call, status, body
+                        )
+                        .into(),
+                    )
+                } else if status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                {
+                    SendError::Retryable(
+                        format!("BigQuery {} returned {}: {}", call, status, body).into(),
+                    )
+                } else {
+                    SendError::Fatal(
+                        format!("BigQuery {} returned {}: {}", call, status, body).into(),
+                    )
+                },
+            );
+        }
+
+        // A body cut off by the timeout or a dropped connection is worth another try
+        response.json().await.map_err(|e| {
+            SendError::Retryable(format!("Invalid BigQuery {} response: {}", call, e).into())
+        })
+    }
+}
+
+#[async_trait]
+impl BigQueryClient for RestClient {
+    async fn query(
+        &self,
+        project_id: &str,
+        request: &QueryRequest,
+    ) -> Result<QueryResponse, Box<dyn Error + Send + Sync>> {
+        let url = format!("{}/projects/{}/queries", self.api_url, project_id);
+        self.send(|| self.http.post(&url).json(request), "jobs.query")
+            .await
+    }
+
+    async fn get_query_results(
+        &self,
+        project_id: &str,
+        job_id: &str,
+        request: &GetQueryResultsRequest,
+    ) -> Result<QueryResponse, Box<dyn Error + Send + Sync>> {
+        let url = format!(
+            "{}/projects/{}/queries/{}",
+            self.api_url, project_id, job_id
+        );
+        self.send(
+            || self.http.get(&url).query(request),
+            "jobs.getQueryResults",
+        )
+        .await
+    }
+}
+
+/// Builds credentials for `scopes`: the service account key from `GOOGLE_SA_KEY`
+/// first, then `GOOGLE_APPLICATION_CREDENTIALS` or the metadata server.
+pub async fn token_source(
+    scopes: &'static [&'static str],
+    sa_key: Option<&Secret>,
+) -> Result<Arc<dyn TokenSource>, Box<dyn Error + Send + Sync>> {
+    let config = Config {
+        scopes: Some(scopes),
+        ..Default::default()
+    };
+    let provider = match sa_key {
+        Some(key) => {
+            log::info!("Using BigQuery credentials from GOOGLE_SA_KEY");
+            let credentials: CredentialsFile = serde_json::from_str(key.expose())
+                .map_err(|e| format!("Failed to parse service account credentials: {}", e))?;
+            DefaultTokenSourceProvider::new_with_credentials(config, Box::new(credentials)).await
+        }
+        None => DefaultTokenSourceProvider::new(config).await,
+    }
+    .map_err(|e| format!("Failed to create BigQuery credentials: {}", e))?;
+    Ok(provider.token_source())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_response_parses_rest_json() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let response: QueryResponse = serde_json::from_str(
+            r#"{
+                "jobReference": {"projectId": "p", "jobId": "job-1", "location": "US"},
+                "jobComplete": true,
+                "totalRows": "2",
+                "pageToken": "next",
+                "rows": [
+                    {"f": [{"v": "video-001"}, {"v": null}]},
+                    {"f": [{"v": [{"v": "a"}]}, {"v": {"f": [{"v": "1"}]}}]}
+                ]
+            }"#,
+        )?;
+
+        assert_eq!(response.job_reference.job_id, "job-1");
+        assert_eq!(response.total_rows, Some(2));
+        let rows = response.rows.unwrap();
+        assert_eq!(rows[0].f[0].v, Value::String("video-001".to_string()));
+        assert_eq!(rows[0].f[1].v, Value::Null);
+        assert!(matches!(rows[1].f[0].v, Value::Array(_)));
+        assert!(matches!(rows[1].f[1].v, Value::Struct(_)));
+
+        let pending: QueryResponse =
+            serde_json::from_str(r#"{"jobReference": {"jobId": "job-2"}, "jobComplete": false}"#)?;
+        assert!(!pending.job_complete);
+        assert_eq!(pending.total_rows, None);
+        Ok(())
+    }
+}
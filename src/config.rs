@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::auth::ApiKeys;
+use crate::decisions::DecisionSinkConfig;
+use crate::index::{IndexConfig, MergePolicy};
+use crate::limits::LimitsConfig;
+use crate::policy::SearchPolicy;
+use crate::source::SourceConfig;
+use crate::wal::FsyncPolicy;
+use crate::writeback::{parse_var, WriteBackConfig};
+
+/// Hashes are 64 bits, so they cannot be split into more blocks than this.
+const HASH_BITS: usize = 64;
+
+/// A setting that must never be printed, such as a service account key.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Compares in constant time, so response timing says nothing about how much
+    /// of `candidate` was right.
+    pub fn matches(&self, candidate: &str) -> bool {
+        let (secret, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+        secret.len() == candidate.len()
+            && secret
+                .iter()
+                .zip(candidate)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Command line flags. The named ones are shortcuts for the most common settings;
+/// `--set` reaches every other one.
+#[derive(Parser, Debug)]
+#[command(about = "Near-duplicate detection service for 64-bit video hashes")]
+struct Cli {
+    /// TOML file to read settings from (or CONFIG_FILE)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Address to listen on (SERVER_BIND)
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<String>,
+    /// Hamming distance used when a request sets none (SEARCH_DEFAULT_MAX_DISTANCE)
+    #[arg(long, value_name = "BITS")]
+    max_distance: Option<String>,
+    /// Blocks the hashes are split into for multi-index hashing (INDEX_MIH_BLOCKS)
+    #[arg(long, value_name = "N")]
+    mih_blocks: Option<String>,
+    /// bigquery, jsonl or csv (HASH_SOURCE)
+    #[arg(long, value_name = "KIND")]
+    hash_source: Option<String>,
+    /// Table the hashes are read from (BIGQUERY_TABLE)
+    #[arg(long, value_name = "TABLE")]
+    bigquery_table: Option<String>,
+    /// SQL condition rows must satisfy (BIGQUERY_FILTER)
+    #[arg(long, value_name = "SQL")]
+    bigquery_filter: Option<String>,
+    /// Any other setting, by its variable name; may be repeated
+    #[arg(long = "set", value_name = "NAME=VALUE")]
+    set: Vec<String>,
+}
+
+/// Raw settings by variable name. Command line flags win over the environment,
+/// which wins over the config file.
+#[derive(Default)]
+pub struct Settings {
+    flags: HashMap<String, String>,
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    /// Parses `args` (exiting on `--help` or an unknown flag), then reads the
+    /// environment and the config file the flags or `CONFIG_FILE` point at.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::from_cli(Cli::parse_from(args))
+    }
+
+    /// Like `load`, but returns flags that do not parse as an error rather than
+    /// exiting, for reloads while serving.
+    pub fn reload(
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::from_cli(Cli::try_parse_from(args)?)
+    }
+
+    fn from_cli(cli: Cli) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let env: HashMap<String, String> = env::vars().collect();
+
+        let mut flags = HashMap::new();
+        for (key, value) in [
+            ("SERVER_BIND", cli.bind),
+            ("SEARCH_DEFAULT_MAX_DISTANCE", cli.max_distance),
+            ("INDEX_MIH_BLOCKS", cli.mih_blocks),
+            ("HASH_SOURCE", cli.hash_source),
+            ("BIGQUERY_TABLE", cli.bigquery_table),
+            ("BIGQUERY_FILTER", cli.bigquery_filter),
+        ] {
+            if let Some(value) = value {
+                flags.insert(key.to_string(), value);
+            }
+        }
+        for setting in cli.set {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid --set '{}', expected NAME=VALUE", setting))?;
+            flags.insert(key.trim().to_uppercase(), value.to_string());
+        }
+
+        let file = match cli
+            .config
+            .or_else(|| env.get("CONFIG_FILE").map(PathBuf::from))
+        {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read config file {:?}: {}", path, e))?;
+                parse_file(&contents)
+                    .map_err(|e| format!("Invalid config file {:?}: {}", path, e))?
+            }
+            None => HashMap::new(),
+        };
+
+        Ok(Self { flags, env, file })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.flags
+            .get(key)
+            .or_else(|| self.env.get(key))
+            .or_else(|| self.file.get(key))
+            .cloned()
+    }
+}
+
+/// Flattens a TOML document into variable names: `[bigquery] table = ...` is
+/// `BIGQUERY_TABLE`, a top-level `hash_source = ...` is `HASH_SOURCE`. Arrays and
+/// tables below a section, such as `filter_params`, are passed on as JSON.
+fn parse_file(contents: &str) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let document: toml::Table = contents.parse()?;
+    let mut settings = HashMap::new();
+    for (key, value) in document {
+        match value {
+            toml::Value::Table(section) => {
+                for (name, value) in section {
+                    settings.insert(
+                        format!("{}_{}", key, name).to_uppercase(),
+                        file_value(value)?,
+                    );
+                }
+            }
+            value => {
+                settings.insert(key.to_uppercase(), file_value(value)?);
+            }
+        }
+    }
+    Ok(settings)
+}
+
+fn file_value(value: toml::Value) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(match value {
+        toml::Value::String(value) => value,
+        toml::Value::Array(_) | toml::Value::Table(_) => serde_json::to_string(&value)?,
+        other => other.to_string(),
+    })
+}
+
+/// Everything the service reads at startup, validated.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// The policy the service starts with; `/admin/search-policy` and SIGHUP change it.
+    pub search: SearchPolicy,
+    /// Answer `/search` with 503 until the index has loaded.
+    pub search_requires_ready: bool,
+    pub index: IndexConfig,
+    pub source: SourceConfig,
+    pub write_back: Option<WriteBackConfig>,
+    pub decisions: Option<DecisionSinkConfig>,
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    pub wal_path: Option<PathBuf>,
+    pub wal_fsync: FsyncPolicy,
+    pub sync_state_path: Option<PathBuf>,
+    /// How often new BigQuery rows are pulled in; `None` turns the sync off.
+    pub sync_interval: Option<Duration>,
+    /// Keys clients must present; with none configured the API is open.
+    pub api_keys: ApiKeys,
+    pub limits: LimitsConfig,
+}
+
+impl Config {
+    pub fn from_settings(settings: &Settings) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::from_lookup(|key| settings.get(key))
+    }
+
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let bind = lookup("SERVER_BIND").unwrap_or_else(|| "0.0.0.0:8080".to_string());
+        let bind = bind.parse().map_err(|_| {
+            format!(
+                "Invalid SERVER_BIND '{}', expected an address such as 0.0.0.0:8080",
+                bind
+            )
+        })?;
+
+        let index_defaults = IndexConfig::default();
+        let index = IndexConfig {
+            mih_blocks: parse_var(&lookup, "INDEX_MIH_BLOCKS", index_defaults.mih_blocks)?,
+            merge_policy: MergePolicy {
+                max_delta: parse_var(
+                    &lookup,
+                    "INDEX_MERGE_MAX_DELTA",
+                    index_defaults.merge_policy.max_delta,
+                )?,
+                max_age: Duration::from_secs(parse_var(
+                    &lookup,
+                    "INDEX_MERGE_MAX_AGE_SECS",
+                    index_defaults.merge_policy.max_age.as_secs(),
+                )?),
+            },
+        };
+        if index.mih_blocks == 0 || index.mih_blocks > HASH_BITS {
+            return Err(format!(
+                "INDEX_MIH_BLOCKS must be between 1 and {}, got {}",
+                HASH_BITS, index.mih_blocks
+            )
+            .into());
+        }
+        // A zero would merge, and so rebuild the whole MIH index, on nearly every write
+        if index.merge_policy.max_delta == 0 || index.merge_policy.max_age.is_zero() {
+            return Err(
+                "INDEX_MERGE_MAX_DELTA and INDEX_MERGE_MAX_AGE_SECS must be at least 1".into(),
+            );
+        }
+
+        let search_requires_ready = match lookup("SEARCH_REQUIRES_READY") {
+            Some(value) => parse_flag("SEARCH_REQUIRES_READY", &value)?,
+            None => true,
+        };
+        let wal_fsync = match lookup("WAL_FSYNC") {
+            Some(value) => value.parse()?,
+            None => FsyncPolicy::Always,
+        };
+        let sync_interval = match lookup("BIGQUERY_SYNC_INTERVAL_SECS") {
+            Some(_) => Some(Duration::from_secs(parse_var(
+                &lookup,
+                "BIGQUERY_SYNC_INTERVAL_SECS",
+                0,
+            )?)),
+            None => None,
+        };
+        if sync_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err("BIGQUERY_SYNC_INTERVAL_SECS must be at least 1".into());
+        }
+        let snapshot_interval =
+            Duration::from_secs(parse_var(&lookup, "SNAPSHOT_INTERVAL_SECS", 300)?);
+        if snapshot_interval.is_zero() {
+            return Err("SNAPSHOT_INTERVAL_SECS must be at least 1".into());
+        }
+        let source = SourceConfig::from_lookup(&lookup)?;
+        // File sources have no watermark, so every sync would re-upsert the whole
+        // file and bring back whatever was deleted since
+        if sync_interval.is_some() && !matches!(source, SourceConfig::BigQuery(_)) {
+            return Err("BIGQUERY_SYNC_INTERVAL_SECS needs HASH_SOURCE=bigquery".into());
+        }
+
+        Ok(Self {
+            bind,
+            search: SearchPolicy::from_lookup(&lookup)?,
+            search_requires_ready,
+            index,
+            source,
+            write_back: WriteBackConfig::from_lookup(&lookup)?,
+            decisions: DecisionSinkConfig::from_lookup(&lookup)?,
+            snapshot_path: lookup("SNAPSHOT_PATH").map(PathBuf::from),
+            snapshot_interval,
+            wal_path: lookup("WAL_PATH").map(PathBuf::from),
+            wal_fsync,
+            sync_state_path: lookup("SYNC_STATE_PATH").map(PathBuf::from),
+            sync_interval,
+            api_keys: match lookup("API_KEYS") {
+                Some(json) => ApiKeys::from_json(&json)?,
+                None => ApiKeys::default(),
+            },
+            limits: LimitsConfig::from_lookup(&lookup)?,
+        })
+    }
+}
+
+pub(crate) fn parse_flag(key: &str, value: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("Invalid {} '{}', expected true or false", key, value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<Config, Box<dyn Error + Send + Sync>> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_defaults_match_the_previous_constants() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let config = config_from(&[])?;
+        assert_eq!(config.bind, "0.0.0.0:8080".parse()?);
+        assert_eq!(config.search, SearchPolicy::default());
+        assert_eq!(config.index.mih_blocks, 8);
+        assert!(config.search_requires_ready);
+        assert_eq!(config.source, SourceConfig::BigQuery(Box::default()));
+        assert_eq!(config.sync_interval, None);
+        assert_eq!(config.decisions, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_settings_are_overridden_by_env_and_flags(
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let file = parse_file(
+            r#"
+            hash_source = "bigquery"
+
+            [server]
+            bind = "127.0.0.1:9000"
+
+            [search]
+            default_max_distance = 3
+
+            [index]
+            mih_blocks = 4
+
+            [bigquery]
+            table = "staging_ds.videos"
+            filter = "status = @status"
+            filter_params = { status = "live" }
+
+            [[api_keys]]
+            name = "upload-pipeline"
+            key = "search-key-0123456789"
+            scopes = ["search"]
+            "#,
+        )?;
+        assert_eq!(file["BIGQUERY_FILTER_PARAMS"], r#"{"status":"live"}"#);
+
+        let settings = Settings {
+            flags: HashMap::from([("INDEX_MIH_BLOCKS".to_string(), "16".to_string())]),
+            env: HashMap::from([
+                ("SERVER_BIND".to_string(), "127.0.0.1:9100".to_string()),
+                ("INDEX_MIH_BLOCKS".to_string(), "2".to_string()),
+            ]),
+            file,
+        };
+        let config = Config::from_settings(&settings)?;
+
+        assert_eq!(config.bind, "127.0.0.1:9100".parse()?);
+        assert_eq!(config.search.default_max_distance, 3);
+        assert_eq!(config.index.mih_blocks, 16);
+        assert!(!config.api_keys.is_empty());
+        match config.source {
+            SourceConfig::BigQuery(source) => {
+                assert_eq!(source.table, "staging_ds.videos");
+                assert_eq!(source.filter_parameters[0].value, "live");
+            }
+            other => panic!("unexpected source {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        for vars in [
+            vec![("SERVER_BIND", "8080")],
+            vec![("SEARCH_DEFAULT_MAX_DISTANCE", "20")],
+            vec![("SEARCH_MAX_ALLOWED_DISTANCE", "65")],
+            vec![("SEARCH_MIN_SIMILARITY", "-5")],
+            vec![("SEARCH_INSERT_ON_MISS", "sometimes")],
+            vec![("INDEX_MIH_BLOCKS", "0")],
+            vec![("INDEX_MIH_BLOCKS", "eight")],
+            vec![("INDEX_MERGE_MAX_DELTA", "0")],
+            vec![("INDEX_MERGE_MAX_AGE_SECS", "0")],
+            vec![("SEARCH_REQUIRES_READY", "maybe")],
+            vec![("WAL_FSYNC", "sometimes")],
+            vec![("BIGQUERY_SYNC_INTERVAL_SECS", "-1")],
+            vec![("BIGQUERY_SYNC_INTERVAL_SECS", "0")],
+            vec![("SNAPSHOT_INTERVAL_SECS", "0")],
+            vec![("HASH_SOURCE", "jsonl")],
+            vec![("DECISION_SINK", "kafka")],
+            vec![("DECISION_SINK", "ndjson")],
+            vec![
+                ("DECISION_SINK", "ndjson"),
+                ("DECISION_SINK_PATH", "decisions.ndjson"),
+                ("DECISION_BATCH_SIZE", "0"),
+            ],
+            vec![("API_KEYS", r#"{"name": "ops"}"#)],
+        ] {
+            assert!(config_from(&vars).is_err(), "{:?} was accepted", vars);
+        }
+        assert!(parse_file("[server\nbind = 1").is_err());
+        // A reload must not exit the process over a flag it cannot parse
+        let args = ["videohash-indexer", "--no-such-flag"].map(String::from);
+        assert!(Settings::reload(args).is_err());
+
+        // File sources cannot sync incrementally
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let vars = [("HASH_SOURCE", "csv"), ("HASH_SOURCE_PATH", manifest)];
+        assert!(config_from(&vars).is_ok());
+        let err =
+            config_from(&[vars[0], vars[1], ("BIGQUERY_SYNC_INTERVAL_SECS", "60")]).unwrap_err();
+        assert!(err.to_string().contains("HASH_SOURCE=bigquery"), "{}", err);
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let config = config_from(&[
+            ("GOOGLE_SA_KEY", r#"{"private_key": "hunter2"}"#),
+            (
+                "API_KEYS",
+                r#"[{"name": "ops", "key": "hunter3-0123456789", "scopes": ["admin"]}]"#,
+            ),
+            ("BIGQUERY_WRITEBACK_TABLE", "project.ds.changes"),
+        ])?;
+        let printed = format!("{:#?}", config);
+        assert!(!printed.contains("hunter"), "{}", printed);
+        assert!(printed.contains("<redacted>"));
+        Ok(())
+    }
+}
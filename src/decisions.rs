@@ -0,0 +1,403 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use google_cloud_token::TokenSource;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::config::Secret;
+use crate::writeback::{self, InsertAllClient, TableRef, MAX_INSERT_ROWS};
+
+/// A search that found an existing video within the duplicate threshold.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DecisionEvent {
+    /// The video that was searched for.
+    pub video_id: String,
+    /// The nearest indexed video it was judged a duplicate of.
+    pub matched_video_id: String,
+    pub hamming_distance: u32,
+    /// The `max_distance` the decision was made with.
+    pub threshold: u32,
+    /// Whether the search was read-only (`insert: false`).
+    pub read_only: bool,
+    pub decided_at: String,
+}
+
+impl DecisionEvent {
+    pub fn new(
+        video_id: &str,
+        (matched_video_id, hamming_distance): &(String, u32),
+        threshold: u32,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            video_id: video_id.to_string(),
+            matched_video_id: matched_video_id.clone(),
+            hamming_distance: *hamming_distance,
+            threshold,
+            read_only,
+            decided_at: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        }
+    }
+}
+
+/// Somewhere batches of decision events are written.
+#[async_trait]
+pub trait DecisionSink: Send + Sync {
+    fn name(&self) -> String;
+
+    async fn write(&self, events: Vec<DecisionEvent>) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Appends one JSON object per line to a local file.
+pub struct NdjsonSink {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl DecisionSink for NdjsonSink {
+    fn name(&self) -> String {
+        format!("NDJSON file {:?}", self.path)
+    }
+
+    async fn write(&self, events: Vec<DecisionEvent>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut lines = Vec::new();
+        for event in events.iter() {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| format!("Failed to open decision log {:?}: {}", self.path, e))?;
+        file.write_all(&lines).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Streams events into a BigQuery table with `insertAll`. The table needs a column
+/// for every field of `DecisionEvent`, with `decided_at` as a `TIMESTAMP`.
+pub struct BigQueryDecisionSink {
+    table: TableRef,
+    client: InsertAllClient,
+}
+
+impl BigQueryDecisionSink {
+    pub fn new(table: TableRef, auth: Arc<dyn TokenSource>) -> Self {
+        Self {
+            client: InsertAllClient::new(&table, auth, 3, Duration::from_millis(500)),
+            table,
+        }
+    }
+}
+
+#[async_trait]
+impl DecisionSink for BigQueryDecisionSink {
+    fn name(&self) -> String {
+        format!("BigQuery table {}", self.table)
+    }
+
+    async fn write(&self, events: Vec<DecisionEvent>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rows = events
+            .into_iter()
+            .map(|event| {
+                let insert_id = format!(
+                    "{}:{}:{}",
+                    event.video_id, event.matched_video_id, event.decided_at
+                );
+                (insert_id, event)
+            })
+            .collect();
+        match self.client.insert(rows).await {
+            0 => Ok(()),
+            dropped => Err(format!("{} events were not accepted", dropped).into()),
+        }
+    }
+}
+
+/// Buffering and batching for the decision log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecisionLogConfig {
+    /// Events held in memory waiting for the sink; further events are dropped.
+    pub buffer_size: usize,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+impl Default for DecisionLogConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 10_000,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Handle for recording decisions; cheap to clone. Recording never blocks: when the
+/// buffer is full the event is dropped and counted. The background writer stops
+/// once every handle is dropped and the buffer is drained.
+#[derive(Clone)]
+pub struct DecisionLog {
+    sender: mpsc::Sender<DecisionEvent>,
+    counters: Arc<Counters>,
+}
+
+impl DecisionLog {
+    pub fn start(
+        sink: Arc<dyn DecisionSink>,
+        config: DecisionLogConfig,
+    ) -> (Self, tokio::task::JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel(config.buffer_size);
+        let counters = Arc::new(Counters::default());
+
+        let worker_counters = counters.clone();
+        let worker = tokio::spawn(async move {
+            let mut reported_drops = 0;
+            while let Some(batch) =
+                writeback::next_batch(&mut receiver, config.batch_size, config.flush_interval).await
+            {
+                let count = batch.len() as u64;
+                match sink.write(batch).await {
+                    Ok(()) => {
+                        worker_counters.written.fetch_add(count, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        worker_counters.failed.fetch_add(count, Ordering::Relaxed);
+                        log::error!(
+                            "Failed to write {} decision events to {}: {}",
+                            count,
+                            sink.name(),
+                            e
+                        );
+                    }
+                }
+
+                let dropped = worker_counters.dropped.load(Ordering::Relaxed);
+                if dropped > reported_drops {
+                    log::warn!(
+                        "Dropped {} decision events because the buffer was full ({} in total)",
+                        dropped - reported_drops,
+                        dropped
+                    );
+                    reported_drops = dropped;
+                }
+            }
+        });
+
+        (Self { sender, counters }, worker)
+    }
+
+    pub fn record(&self, event: DecisionEvent) {
+        if self.sender.try_send(event).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Events the sink accepted.
+    pub fn written(&self) -> u64 {
+        self.counters.written.load(Ordering::Relaxed)
+    }
+
+    /// Events dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Events lost because the sink returned an error.
+    pub fn failed(&self) -> u64 {
+        self.counters.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// Where `DECISION_SINK` sends decision events.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecisionTarget {
+    Ndjson(PathBuf),
+    BigQuery(TableRef),
+}
+
+/// The decision log as configured by the `DECISION_*` settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecisionSinkConfig {
+    pub target: DecisionTarget,
+    pub log: DecisionLogConfig,
+    /// Service account key from `GOOGLE_SA_KEY`, for the BigQuery target.
+    pub credentials: Option<Secret>,
+}
+
+impl DecisionSinkConfig {
+    /// Reads `DECISION_SINK` (`ndjson` or `bigquery`) and friends. Returns `None`
+    /// when it is unset.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let target = match lookup("DECISION_SINK").as_deref() {
+            None => return Ok(None),
+            Some("ndjson") => DecisionTarget::Ndjson(
+                lookup("DECISION_SINK_PATH")
+                    .map(PathBuf::from)
+                    .ok_or("DECISION_SINK=ndjson requires DECISION_SINK_PATH")?,
+            ),
+            Some("bigquery") => DecisionTarget::BigQuery(
+                TableRef::from_lookup("DECISION_SINK_TABLE", &lookup)?
+                    .ok_or("DECISION_SINK=bigquery requires DECISION_SINK_TABLE")?,
+            ),
+            Some(other) => {
+                return Err(format!(
+                    "Unknown DECISION_SINK '{}', expected 'ndjson' or 'bigquery'",
+                    other
+                )
+                .into())
+            }
+        };
+
+        let defaults = DecisionLogConfig::default();
+        let log = DecisionLogConfig {
+            buffer_size: writeback::parse_var(
+                &lookup,
+                "DECISION_BUFFER_SIZE",
+                defaults.buffer_size,
+            )?,
+            batch_size: writeback::parse_var(&lookup, "DECISION_BATCH_SIZE", defaults.batch_size)?,
+            flush_interval: Duration::from_millis(writeback::parse_var(
+                &lookup,
+                "DECISION_FLUSH_MS",
+                defaults.flush_interval.as_millis() as u64,
+            )?),
+        };
+        if log.buffer_size == 0 || log.batch_size == 0 || log.batch_size > MAX_INSERT_ROWS {
+            return Err(
+                "DECISION_BUFFER_SIZE must be positive and DECISION_BATCH_SIZE 1..=50000".into(),
+            );
+        }
+
+        Ok(Some(Self {
+            target,
+            log,
+            credentials: lookup("GOOGLE_SA_KEY").map(Secret::new),
+        }))
+    }
+
+    /// Opens the sink and starts the background writer.
+    pub async fn start(
+        self,
+    ) -> Result<(DecisionLog, tokio::task::JoinHandle<()>), Box<dyn Error + Send + Sync>> {
+        let sink: Arc<dyn DecisionSink> = match self.target {
+            DecisionTarget::Ndjson(path) => Arc::new(NdjsonSink { path }),
+            DecisionTarget::BigQuery(table) => Arc::new(BigQueryDecisionSink::new(
+                table,
+                writeback::default_token_source(self.credentials.as_ref()).await?,
+            )),
+        };
+
+        log::info!("Recording duplicate decisions to {}", sink.name());
+        Ok(DecisionLog::start(sink, self.log))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct MemorySink(Mutex<Vec<DecisionEvent>>);
+
+    #[async_trait]
+    impl DecisionSink for MemorySink {
+        fn name(&self) -> String {
+            "memory".to_string()
+        }
+
+        async fn write(
+            &self,
+            events: Vec<DecisionEvent>,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().extend(events);
+            Ok(())
+        }
+    }
+
+    fn event(n: u32) -> DecisionEvent {
+        DecisionEvent::new(
+            &format!("video-{}", n),
+            &("video-000".to_string(), n),
+            5,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_full_buffer_drops_and_counts() {
+        let sink = Arc::new(MemorySink(Mutex::new(Vec::new())));
+        let (log, worker) = DecisionLog::start(
+            sink.clone(),
+            DecisionLogConfig {
+                buffer_size: 2,
+                batch_size: 10,
+                flush_interval: Duration::from_millis(10),
+            },
+        );
+
+        // The worker cannot run until this task yields, so only two events fit
+        for n in 0..5 {
+            log.record(event(n));
+        }
+        assert_eq!(log.dropped(), 3);
+
+        // Counters outlive the handle, which has to go for the worker to finish
+        let counters = log.counters.clone();
+        drop(log);
+        worker.await.unwrap();
+        assert_eq!(counters.written.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.failed.load(Ordering::Relaxed), 0);
+        let written: Vec<String> = sink
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.video_id.clone())
+            .collect();
+        assert_eq!(written, vec!["video-0", "video-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_sink_appends() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path =
+            std::env::temp_dir().join(format!("videohash-decisions-{}.ndjson", std::process::id()));
+        let sink = NdjsonSink { path: path.clone() };
+        sink.write(vec![event(1)]).await?;
+        sink.write(vec![event(2), event(3)]).await?;
+
+        let contents = std::fs::read_to_string(&path)?;
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2]["video_id"], "video-3");
+        assert_eq!(lines[2]["matched_video_id"], "video-000");
+        assert_eq!(lines[2]["hamming_distance"], 3);
+        assert_eq!(lines[2]["threshold"], 5);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
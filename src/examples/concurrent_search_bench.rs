@@ -0,0 +1,96 @@
+// examples/concurrent_search_bench.rs
+//
+// Measures search throughput with an increasing number of threads while a writer
+// keeps adding hashes. Usage: cargo run --release --example concurrent_search_bench [num_hashes]
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use videohash_indexer::index::spawn_merge_worker;
+use videohash_indexer::{VideoHash, VideoHashIndex};
+
+const SEARCHES_PER_THREAD: usize = 20_000;
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn to_hash(code: u64) -> VideoHash {
+    VideoHash {
+        hash: format!("{:064b}", code),
+    }
+}
+
+fn main() {
+    let num_hashes: usize = env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let index = Arc::new(VideoHashIndex::new());
+    let mut state = 0x9E3779B97F4A7C15u64;
+    let video_hashes: Vec<(String, VideoHash)> = (0..num_hashes)
+        .map(|i| (format!("video-{}", i), to_hash(xorshift(&mut state))))
+        .collect();
+    index.replace_all(&video_hashes).unwrap();
+    println!("Indexed {} hashes", index.len());
+    spawn_merge_worker(index.clone(), Duration::from_secs(1));
+
+    // Keep a writer busy so searches have to run against changing snapshots
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let index = index.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut state = 0xD1B54A32D192ED03u64;
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                index
+                    .add(format!("live-{}", i), &to_hash(xorshift(&mut state)))
+                    .unwrap();
+                i += 1;
+                thread::sleep(Duration::from_micros(100));
+            }
+        })
+    };
+
+    let max_threads = thread::available_parallelism().map_or(8, |n| n.get());
+    let mut baseline = None;
+    let mut threads = 1;
+    while threads <= max_threads {
+        let started = Instant::now();
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                let index = index.clone();
+                thread::spawn(move || {
+                    let mut state = 0xA0761D6478BD642Fu64 ^ t as u64;
+                    for _ in 0..SEARCHES_PER_THREAD {
+                        let query = to_hash(xorshift(&mut state));
+                        index.find_within_distance(&query, 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let elapsed = started.elapsed().as_secs_f64();
+        let throughput = (threads * SEARCHES_PER_THREAD) as f64 / elapsed;
+        let speedup = throughput / *baseline.get_or_insert(throughput);
+        println!(
+            "{:>2} threads: {:>10.0} searches/s ({:.2}x single-thread)",
+            threads, throughput, speedup
+        );
+        threads *= 2;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
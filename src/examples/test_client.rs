@@ -10,6 +10,8 @@ struct SearchRequest {
     hash: String,
 }
 
+// Fields are only read through the Debug output below
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct VideoMatch {
     video_id: String,
@@ -17,6 +19,7 @@ struct VideoMatch {
     is_duplicate: bool,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct SearchResponse {
     match_found: bool,
@@ -27,6 +30,12 @@ struct SearchResponse {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let client = Client::new();
+    // A key with both scopes, when the server has API_KEYS configured
+    let api_key = std::env::var("API_KEY").ok();
+    let with_key = |req: reqwest::RequestBuilder| match &api_key {
+        Some(key) => req.bearer_auth(key),
+        None => req,
+    };
 
     // Add first hash
     let req1 = SearchRequest {
@@ -34,8 +43,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         hash: "0".repeat(64),
     };
 
-    let resp1 = client
-        .post("http://localhost:8080/search")
+    let resp1 = with_key(client.post("http://localhost:8080/search"))
         .json(&req1)
         .send()
         .await?
@@ -50,8 +58,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         hash: "0".repeat(60) + "1111",
     };
 
-    let resp2 = client
-        .post("http://localhost:8080/search")
+    let resp2 = with_key(client.post("http://localhost:8080/search"))
         .json(&req2)
         .send()
         .await?
@@ -61,8 +68,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("Second response: {:?}", resp2);
 
     // Delete first hash
-    let delete_resp = client
-        .delete("http://localhost:8080/hash/video-001")
+    let delete_resp = with_key(client.delete("http://localhost:8080/hash/video-001"))
         .send()
         .await?;
 
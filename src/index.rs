@@ -1,8 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::sync::{Arc, RwLock};
-
-use crate::bigquery;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::bigquery::Watermark;
+use crate::metrics;
+use crate::persistence;
+use crate::retry::RetryPolicy;
+use crate::source::HashSource;
+use crate::sync::{Mutex, MutexGuard, RwLock};
+use crate::wal::{self, FsyncPolicy, MutationLog, WalRecord};
 use mih_rs::Index;
 
 use super::videohash::VideoHash;
@@ -15,16 +29,585 @@ fn binary_string_to_u64(binary_str: &str) -> Result<u64, Box<dyn Error + Send +
     u64::from_str_radix(binary_str, 2).map_err(|e| format!("Invalid binary string: {}", e).into())
 }
 
+/// Controls when the delta layer is folded back into a freshly built MIH index.
+#[derive(Clone, Copy, Debug)]
+pub struct MergePolicy {
+    /// Merge once pending inserts plus tombstones reach this many entries.
+    pub max_delta: usize,
+    /// Merge once the oldest pending change is this old.
+    pub max_age: Duration,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self {
+            max_delta: 10_000,
+            max_age: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How the index is built; fixed for the life of the index.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexConfig {
+    /// Number of blocks the 64-bit hashes are split into for multi-index hashing.
+    pub mih_blocks: usize,
+    pub merge_policy: MergePolicy,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            mih_blocks: 8,
+            merge_policy: MergePolicy::default(),
+        }
+    }
+}
+
+/// A single change to the index, replayed onto a new base after a rebase.
+#[derive(Clone, Debug, PartialEq)]
+enum Mutation {
+    Insert(String, u64),
+    Remove(String),
+}
+
+impl Mutation {
+    fn to_record(&self) -> WalRecord {
+        match self {
+            Mutation::Insert(video_id, hash) => WalRecord::Insert {
+                video_id: video_id.clone(),
+                hash: *hash,
+            },
+            Mutation::Remove(video_id) => WalRecord::Remove {
+                video_id: video_id.clone(),
+            },
+        }
+    }
+
+    fn from_record(record: &WalRecord) -> Option<Self> {
+        match record {
+            WalRecord::Insert { video_id, hash } => Some(Mutation::Insert(video_id.clone(), *hash)),
+            WalRecord::Remove { video_id } => Some(Mutation::Remove(video_id.clone())),
+            WalRecord::Rebuild => None,
+        }
+    }
+}
+
+/// An immutable MIH index over a fixed set of hashes.
+#[derive(Default)]
+struct BaseSegment {
+    index: Option<Index<u64>>,
+    video_ids: Vec<String>,
+    codes: Vec<u64>,
+    positions: HashMap<String, usize>,
+}
+
+impl BaseSegment {
+    fn build(
+        entries: Vec<(String, u64)>,
+        blocks: usize,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if entries.is_empty() {
+            return Ok(Self::default());
+        }
+
+        // Keep video_ids and codes in the same order so MIH answers map back to ids
+        let (video_ids, codes): (Vec<String>, Vec<u64>) = entries.into_iter().unzip();
+        let positions = video_ids
+            .iter()
+            .enumerate()
+            .map(|(pos, video_id)| (video_id.clone(), pos))
+            .collect();
+
+        // Create the index with an explicit number of blocks (8 by default for 64-bit hashes)
+        // This is more appropriate than Index::new() which might choose inappropriate parameters
+        let index = mih_rs::Index::with_blocks(codes.clone(), blocks)
+            .map_err(|e| format!("Failed to create MIH index: {}", e))?;
+
+        Ok(Self {
+            index: Some(index),
+            video_ids,
+            codes,
+            positions,
+        })
+    }
+
+    fn get(&self, video_id: &str) -> Option<u64> {
+        self.positions.get(video_id).map(|&pos| self.codes[pos])
+    }
+}
+
+/// An immutable, searchable view of the index: a built MIH segment plus a small delta.
+///
+/// Inserts land in `delta` and are scanned linearly. Deletions of ids that live
+/// in `base` (and overwrites of them) are recorded in `tombstones` so the base
+/// segment never has to be rebuilt on the request path. Writers never modify a
+/// published snapshot; they clone the delta and publish a replacement.
+#[derive(Clone, Default)]
+struct IndexSnapshot {
+    base: Arc<BaseSegment>,
+    delta: HashMap<String, u64>,
+    tombstones: HashSet<String>,
+    delta_since: Option<Instant>,
+    /// Bumped on every publish so callers can tell whether anything changed.
+    generation: u64,
+}
+
+impl IndexSnapshot {
+    fn with_base(base: BaseSegment) -> Self {
+        Self {
+            base: Arc::new(base),
+            ..Default::default()
+        }
+    }
+
+    fn get(&self, video_id: &str) -> Option<u64> {
+        if let Some(&code) = self.delta.get(video_id) {
+            return Some(code);
+        }
+        if self.tombstones.contains(video_id) {
+            return None;
+        }
+        self.base.get(video_id)
+    }
+
+    fn len(&self) -> usize {
+        // Tombstones only ever name base ids, and every base id present in the
+        // delta is tombstoned, so the three sets never double count.
+        self.base.codes.len() - self.tombstones.len() + self.delta.len()
+    }
+
+    fn entries(&self) -> Vec<(String, u64)> {
+        let mut entries: Vec<(String, u64)> = self
+            .base
+            .video_ids
+            .iter()
+            .zip(self.base.codes.iter())
+            .filter(|(video_id, _)| !self.tombstones.contains(*video_id))
+            .map(|(video_id, &code)| (video_id.clone(), code))
+            .collect();
+        entries.extend(
+            self.delta
+                .iter()
+                .map(|(video_id, &code)| (video_id.clone(), code)),
+        );
+        entries
+    }
+
+    fn apply(&mut self, mutation: &Mutation) -> bool {
+        let changed = match mutation {
+            Mutation::Insert(video_id, code) => {
+                if self.base.positions.contains_key(video_id) {
+                    self.tombstones.insert(video_id.clone());
+                }
+                self.delta.insert(video_id.clone(), *code);
+                true
+            }
+            Mutation::Remove(video_id) => {
+                let existed = self.get(video_id).is_some();
+                self.delta.remove(video_id);
+                if self.base.positions.contains_key(video_id) {
+                    self.tombstones.insert(video_id.clone());
+                }
+                existed
+            }
+        };
+
+        if changed && self.delta_since.is_none() {
+            self.delta_since = Some(Instant::now());
+        }
+        changed
+    }
+
+    /// Whether `apply` would change anything, without applying it.
+    fn would_change(&self, mutation: &Mutation) -> bool {
+        match mutation {
+            Mutation::Insert(..) => true,
+            Mutation::Remove(video_id) => self.get(video_id).is_some(),
+        }
+    }
+
+    fn pending_changes(&self) -> usize {
+        self.delta.len() + self.tombstones.len()
+    }
+
+    fn needs_merge(&self, policy: &MergePolicy) -> bool {
+        match self.delta_since {
+            Some(since) => {
+                self.pending_changes() >= policy.max_delta || since.elapsed() >= policy.max_age
+            }
+            None => false,
+        }
+    }
+
+    fn within_distance(&self, hash_value: u64, max_distance: u32) -> Vec<(String, u32)> {
+        let mut neighbors = Vec::new();
+
+        if let Some(index) = &self.base.index {
+            let mut searcher = index.range_searcher();
+            let answers = searcher.run(hash_value, max_distance as usize);
+
+            for idx in answers {
+                let idx_usize = *idx as usize;
+                if idx_usize < self.base.video_ids.len() {
+                    let video_id = &self.base.video_ids[idx_usize];
+                    if self.tombstones.contains(video_id) {
+                        continue;
+                    }
+                    let hamming_dist = (hash_value ^ self.base.codes[idx_usize]).count_ones();
+                    neighbors.push((video_id.clone(), hamming_dist));
+                }
+            }
+        }
+
+        for (video_id, &stored_hash) in self.delta.iter() {
+            let hamming_dist = (hash_value ^ stored_hash).count_ones();
+            if hamming_dist <= max_distance {
+                neighbors.push((video_id.clone(), hamming_dist));
+            }
+        }
+
+        neighbors.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        neighbors
+    }
+
+    /// The `k` nearest hashes, sorted by distance and then video_id.
+    fn nearest(
+        &self,
+        hash_value: u64,
+        k: usize,
+    ) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Use the base segment's top-k to bound the search radius, then collect
+        // everything inside it so ties at the k-th distance resolve by video_id
+        let mut radius = 64;
+        if let Some(index) = &self.base.index {
+            let mut searcher = index.topk_searcher();
+            // Some candidates may be tombstoned. Start with a small margin and double
+            // it until k survive, rather than over-fetching by every tombstone. Once
+            // the base has no more hashes than that, every one is a candidate anyway.
+            let mut candidates = k + self.tombstones.len().min(k);
+            while candidates < self.base.codes.len() {
+                let mut live = Vec::with_capacity(candidates);
+                for idx in searcher.run(hash_value, candidates).iter() {
+                    let idx_usize = *idx as usize;
+                    if idx_usize >= self.base.video_ids.len() {
+                        return Err("Index inconsistency: invalid vector index".into());
+                    }
+                    if !self.tombstones.contains(&self.base.video_ids[idx_usize]) {
+                        live.push((hash_value ^ self.base.codes[idx_usize]).count_ones());
+                    }
+                }
+                if live.len() >= k {
+                    radius = *live.select_nth_unstable(k - 1).1;
+                    break;
+                }
+                candidates = candidates.saturating_mul(2);
+            }
+        }
+
+        let mut neighbors = self.within_distance(hash_value, radius);
+        neighbors.truncate(k);
+        Ok(neighbors)
+    }
+}
+
+/// State only touched by writers, serialized by `VideoHashIndex::writer`.
+#[derive(Default)]
+struct WriterState {
+    /// Mutations published while a new base segment is being built off to the side.
+    /// `Some` marks a rebase in progress.
+    rebase_journal: Option<Vec<Mutation>>,
+    /// Every published mutation is appended here first when set.
+    log: Option<MutationLog>,
+}
+
+/// An in-progress rebase. Dropping it without calling `finish` (an error or a
+/// panic while building the new base) discards the journal so later rebases can start.
+struct Rebase<'a> {
+    index: &'a VideoHashIndex,
+    captured: Arc<IndexSnapshot>,
+    /// The new contents come from outside the index, so the log gets a rebuild marker.
+    rebuild: bool,
+    finished: bool,
+}
+
+impl Rebase<'_> {
+    /// Builds a new base segment from `entries` without blocking readers or writers,
+    /// then replays the journaled writes onto it and publishes the result.
+    fn finish(mut self, entries: Vec<(String, u64)>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Only rebuilds count as a swap; merges keep the same contents
+        let _swapping = self.rebuild.then(|| Swapping::start(&self.index.swapping));
+        let cause = if self.rebuild { "rebuild" } else { "merge" };
+        let timer = metrics::MIH_BUILDS
+            .with_label_values(&[cause])
+            .start_timer();
+        let base = BaseSegment::build(entries, self.index.config.mih_blocks)?;
+        timer.observe_duration();
+
+        let mut writer = self.index.lock_writer()?;
+        let journal = writer.rebase_journal.take().unwrap_or_default();
+        if let (true, Some(log)) = (self.rebuild, writer.log.as_mut()) {
+            // Replay after a crash starts from the marker, so the writes that raced
+            // the rebuild are logged again behind it
+            log.append(&WalRecord::Rebuild)?;
+            for mutation in journal.iter() {
+                log.append(&mutation.to_record())?;
+            }
+        }
+        let mut next = IndexSnapshot::with_base(base);
+        for mutation in journal.iter() {
+            next.apply(mutation);
+        }
+        self.index.publish(next);
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+/// Keeps `VideoHashIndex::swapping` set while a rebuilt base is built and published.
+struct Swapping<'a>(&'a AtomicBool);
+
+impl<'a> Swapping<'a> {
+    fn start(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Ordering::Release);
+        Self(flag)
+    }
+}
+
+impl Drop for Swapping<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for Rebase<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Ok(mut writer) = self.index.lock_writer() {
+                writer.rebase_journal = None;
+            }
+        }
+    }
+}
+
+/// Result of `VideoHashIndex::search_or_insert`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchOutcome {
+    /// The video_id is already indexed with exactly this hash; nothing changed.
+    ExactMatch,
+    /// Hashes within the threshold, nearest first; nothing was inserted.
+    Matches(Vec<(String, u32)>),
+    /// Nothing was within the threshold, so the hash was inserted.
+    Inserted,
+    /// Nothing was within the threshold; returned by read-only searches, which
+    /// never insert.
+    NoMatch,
+}
+
+/// What `VideoHashIndex::recover` could not restore on its own.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// The log recorded a rebuild after the snapshot, so the index must be reloaded
+    /// from its source before `pending` is replayed.
+    pub rebuild_required: bool,
+    /// Records to replay with `VideoHashIndex::replay` once the rebuild is done.
+    pub pending: Vec<WalRecord>,
+}
+
+/// The last completed load from a hash source.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadRecord {
+    pub source: String,
+    pub finished_at: String,
+    pub duration_ms: u64,
+    /// Rows the source produced.
+    pub rows: usize,
+    /// Hashes in the index right after the swap, including writes replayed on top.
+    pub indexed: usize,
+}
+
+/// The last failed rebuild or sync.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadError {
+    pub source: String,
+    pub failed_at: String,
+    pub error: String,
+}
+
+#[derive(Default)]
+struct LoadHistory {
+    rebuild_started: Option<Instant>,
+    last_rebuild: Option<LoadRecord>,
+    last_error: Option<LoadError>,
+}
+
+/// A point-in-time view of the index for the status endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct IndexStatus {
+    pub ready: bool,
+    pub indexed: usize,
+    /// Whether the MIH segment is built; an empty index has none.
+    pub mih_built: bool,
+    /// Hashes in the MIH segment; the rest are in the linearly scanned delta.
+    pub mih_size: usize,
+    /// Inserts and deletions waiting for the next merge.
+    pub pending_changes: usize,
+    pub rebuild_in_progress: bool,
+    /// A rebuilt MIH segment is being built or swapped in.
+    pub swapping: bool,
+    pub last_rebuild: Option<LoadRecord>,
+    pub last_error: Option<LoadError>,
+}
+
+/// Read-mostly index of video hashes.
+///
+/// Searches load the current `IndexSnapshot` and run against it without holding
+/// any lock, so they proceed in parallel with each other and with writers.
+/// Writers serialize on `writer`, build a new snapshot from the current one and
+/// publish it with a pointer swap.
+///
+/// Lock order is fixed: `snapshot_file`, then `writer`, then `current`. `writer` may be held while taking `current`, never the
+/// reverse. `current` is only touched by `snapshot` and `publish`, which hold it
+/// just long enough to clone or replace an `Arc` and take no other lock meanwhile.
 pub struct VideoHashIndex {
-    hashes: RwLock<HashMap<String, u64>>,
-    index: RwLock<Option<(Index<u64>, Vec<String>)>>, // Store video_ids alongside the index
+    current: RwLock<Arc<IndexSnapshot>>,
+    writer: Mutex<WriterState>,
+    /// Serializes snapshot file writes so two savers never race on the temp file.
+    snapshot_file: Mutex<()>,
+    /// Newest BigQuery row ingested, where the next incremental sync resumes.
+    /// Taken on its own, never while holding another lock.
+    watermark: Mutex<Option<Watermark>>,
+    /// Set once the contents came from a completed load rather than being whatever
+    /// was left after a failed one.
+    ready: AtomicBool,
+    swapping: AtomicBool,
+    /// What the status endpoint reports about loads. Taken on its own.
+    history: Mutex<LoadHistory>,
+    config: IndexConfig,
+}
+
+impl Default for VideoHashIndex {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VideoHashIndex {
     pub fn new() -> Self {
+        Self::with_config(IndexConfig::default())
+    }
+
+    pub fn with_merge_policy(merge_policy: MergePolicy) -> Self {
+        Self::with_config(IndexConfig {
+            merge_policy,
+            ..IndexConfig::default()
+        })
+    }
+
+    pub fn with_config(config: IndexConfig) -> Self {
         Self {
-            hashes: RwLock::new(HashMap::new()),
-            index: RwLock::new(None),
+            current: RwLock::new(Arc::new(IndexSnapshot::default())),
+            writer: Mutex::new(WriterState::default()),
+            snapshot_file: Mutex::new(()),
+            watermark: Mutex::new(None),
+            ready: AtomicBool::new(false),
+            swapping: AtomicBool::new(false),
+            history: Mutex::new(LoadHistory::default()),
+            config,
+        }
+    }
+
+    /// Returns the currently published snapshot. The lock is only held for the `Arc` clone.
+    fn snapshot(&self) -> Arc<IndexSnapshot> {
+        // Snapshots are only ever replaced wholesale, so even a poisoned lock
+        // still guards a consistent one
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    fn publish(&self, mut snapshot: IndexSnapshot) {
+        let mut current = match self.current.write() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        snapshot.generation = current.generation + 1;
+        *current = Arc::new(snapshot);
+    }
+
+    /// Takes the writer lock, turning poisoning into an error instead of a panic.
+    ///
+    /// A writer that panicked cannot have published a half-applied change, so the
+    /// poison flag is cleared and the next writer proceeds normally.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, WriterState>, Box<dyn Error + Send + Sync>> {
+        self.writer.lock().map_err(|_| {
+            #[cfg(not(videohash_loom))]
+            self.writer.clear_poison();
+            log::error!("Index writer lock was poisoned by a panicking writer");
+            "Index writer lock was poisoned; the operation was not applied, please retry".into()
+        })
+    }
+
+    /// Applies a mutation on top of the current snapshot and publishes the result.
+    /// Must be called with the writer lock held.
+    ///
+    /// The mutation is logged before it is published; if logging fails nothing changes.
+    fn apply_locked(
+        &self,
+        writer: &mut WriterState,
+        mutation: Mutation,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self.apply_batch_locked(writer, vec![mutation])? > 0)
+    }
+
+    /// Applies mutations in order and publishes them as one new snapshot, returning
+    /// how many changed something. Must be called with the writer lock held.
+    ///
+    /// During a rebase every mutation is logged and journaled, even one that changes
+    /// nothing now: the new base may hold ids the current snapshot does not, or lack
+    /// ones it does.
+    ///
+    /// If logging fails partway, the mutations logged so far are still published so
+    /// the index never lags behind its log.
+    fn apply_batch_locked(
+        &self,
+        writer: &mut WriterState,
+        mutations: Vec<Mutation>,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mut next = (*self.snapshot()).clone();
+        let mut applied = 0;
+        let mut failure = None;
+
+        for mutation in mutations {
+            if writer.rebase_journal.is_none() && !next.would_change(&mutation) {
+                continue;
+            }
+            if let Some(log) = writer.log.as_mut() {
+                if let Err(e) = log.append(&mutation.to_record()) {
+                    failure = Some(format!("Failed to log index mutation: {}", e));
+                    break;
+                }
+            }
+            if next.apply(&mutation) {
+                applied += 1;
+            }
+            if let Some(journal) = writer.rebase_journal.as_mut() {
+                journal.push(mutation);
+            }
+        }
+
+        if applied > 0 {
+            self.publish(next);
+        }
+        match failure {
+            Some(e) => Err(e.into()),
+            None => Ok(applied),
         }
     }
 
@@ -35,11 +618,8 @@ impl VideoHashIndex {
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        let mut index = self.index.write().unwrap();
-        *index = None;
-
-        let mut hashes = self.hashes.write().unwrap();
-        hashes.insert(video_id, hash_value);
+        let mut writer = self.lock_writer()?;
+        self.apply_locked(&mut writer, Mutation::Insert(video_id, hash_value))?;
 
         Ok(())
     }
@@ -51,85 +631,153 @@ impl VideoHashIndex {
     ) -> Result<bool, Box<dyn Error + Send + Sync>> {
         let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        let hashes = self.hashes.read().unwrap();
-        if let Some(&existing_hash) = hashes.get(video_id) {
-            return Ok(existing_hash == hash_value);
+        Ok(self.snapshot().get(video_id) == Some(hash_value))
+    }
+
+    /// Marks the start of a rebase. Writes published from now on are journaled so they
+    /// can be replayed onto the new base segment.
+    ///
+    /// Returns `None` if another rebase is already in progress.
+    fn try_begin_rebase(&self) -> Result<Option<Rebase<'_>>, Box<dyn Error + Send + Sync>> {
+        let mut writer = self.lock_writer()?;
+        if writer.rebase_journal.is_some() {
+            return Ok(None);
         }
+        writer.rebase_journal = Some(Vec::new());
+
+        Ok(Some(Rebase {
+            index: self,
+            captured: self.snapshot(),
+            rebuild: false,
+            finished: false,
+        }))
+    }
 
-        Ok(false)
+    fn begin_rebase(&self) -> Result<Rebase<'_>, Box<dyn Error + Send + Sync>> {
+        self.try_begin_rebase()?
+            .ok_or_else(|| "Index rebase already in progress".into())
     }
 
-    fn ensure_index_built(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut index_lock = self.index.write().unwrap();
+    /// Like `begin_rebase`, for replacing the contents with data from outside the index.
+    fn begin_rebuild(&self) -> Result<Rebase<'_>, Box<dyn Error + Send + Sync>> {
+        let mut rebase = self.begin_rebase()?;
+        rebase.rebuild = true;
+        Ok(rebase)
+    }
 
-        if index_lock.is_none() {
-            let hashes = self.hashes.read().unwrap();
-            if hashes.is_empty() {
-                *index_lock = None;
-                return Ok(());
-            }
+    /// Folds the delta and tombstones into a freshly built MIH index.
+    pub fn merge_delta(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rebase = self.begin_rebase()?;
+        let entries = rebase.captured.entries();
+        rebase.finish(entries)
+    }
 
-            // Create ordered vectors of video_ids and hash values to ensure consistent ordering
-            let mut video_id_hash_pairs: Vec<(String, u64)> = hashes
-                .iter()
-                .map(|(video_id, &hash)| (video_id.clone(), hash))
-                .collect();
+    /// Merges the delta if it has outgrown the configured size or age thresholds.
+    ///
+    /// Returns whether a merge was performed.
+    pub fn merge_if_needed(&self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if !self.snapshot().needs_merge(&self.config.merge_policy) {
+            return Ok(false);
+        }
+        if self.lock_writer()?.rebase_journal.is_some() {
+            // A rebuild is already producing a fresh base segment
+            return Ok(false);
+        }
 
-            // Split into separate vectors
-            let video_ids: Vec<String> = video_id_hash_pairs
-                .iter()
-                .map(|(id, _)| id.clone())
-                .collect();
-            let codes: Vec<u64> = video_id_hash_pairs.iter().map(|(_, code)| *code).collect();
+        let started = Instant::now();
+        self.merge_delta()?;
+        log::info!(
+            "Merged delta into MIH index with {} hashes in {:?}",
+            self.len(),
+            started.elapsed()
+        );
 
-            // Create the index with explicit number of blocks (8 for 64-bit hashes)
-            // This is more appropriate than Index::new() which might choose inappropriate parameters
-            match mih_rs::Index::with_blocks(codes, 8) {
-                Ok(new_index) => {
-                    *index_lock = Some((new_index, video_ids));
-                }
-                Err(e) => {
-                    return Err(format!("Failed to create MIH index: {}", e).into());
-                }
-            }
+        Ok(true)
+    }
+
+    fn check_existing(
+        snapshot: &IndexSnapshot,
+        video_id: &str,
+        hash_value: u64,
+        max_distance: u32,
+    ) -> Option<SearchOutcome> {
+        if snapshot.get(video_id) == Some(hash_value) {
+            return Some(SearchOutcome::ExactMatch);
         }
 
-        Ok(())
+        let matches = snapshot.within_distance(hash_value, max_distance);
+        observe_distances(&matches);
+        if matches.is_empty() {
+            None
+        } else {
+            Some(SearchOutcome::Matches(matches))
+        }
     }
 
-    pub fn find_nearest_neighbor(
+    /// Searches for hashes within `max_distance` and inserts `hash` only if none
+    /// matched, as one atomic step. Concurrent submissions of near-duplicates
+    /// therefore insert at most one of them.
+    pub fn search_or_insert(
         &self,
+        video_id: String,
         hash: &VideoHash,
-    ) -> Result<Option<(String, u32)>, Box<dyn Error + Send + Sync>> {
+        max_distance: u32,
+    ) -> Result<SearchOutcome, Box<dyn Error + Send + Sync>> {
         let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        self.ensure_index_built()?;
+        // Matches and exact repeats are answered from a snapshot without
+        // touching the writer lock
+        if let Some(outcome) =
+            Self::check_existing(&self.snapshot(), &video_id, hash_value, max_distance)
+        {
+            return Ok(outcome);
+        }
 
-        let index_lock = self.index.read().unwrap();
-        if index_lock.is_none() {
-            return Ok(None);
+        // Re-check under the writer lock, where no other insert can be published
+        // between the search and our own insert
+        let mut writer = self.lock_writer()?;
+        if let Some(outcome) =
+            Self::check_existing(&self.snapshot(), &video_id, hash_value, max_distance)
+        {
+            return Ok(outcome);
         }
+        self.apply_locked(&mut writer, Mutation::Insert(video_id, hash_value))?;
 
-        let (index, video_ids) = index_lock.as_ref().unwrap();
+        Ok(SearchOutcome::Inserted)
+    }
 
-        let mut searcher = index.topk_searcher();
-        let answers = searcher.run(hash_value, 1);
+    /// Same decision as `search_or_insert`, without inserting anything.
+    pub fn search_existing(
+        &self,
+        video_id: &str,
+        hash: &VideoHash,
+        max_distance: u32,
+    ) -> Result<SearchOutcome, Box<dyn Error + Send + Sync>> {
+        let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        if answers.is_empty() {
-            return Ok(None);
-        }
+        Ok(
+            Self::check_existing(&self.snapshot(), video_id, hash_value, max_distance)
+                .unwrap_or(SearchOutcome::NoMatch),
+        )
+    }
 
-        let idx = answers[0] as usize;
-        if idx >= video_ids.len() {
-            return Err("Index inconsistency: invalid vector index".into());
-        }
+    pub fn find_nearest_neighbor(
+        &self,
+        hash: &VideoHash,
+    ) -> Result<Option<(String, u32)>, Box<dyn Error + Send + Sync>> {
+        Ok(self.find_nearest_neighbors(hash, 1)?.into_iter().next())
+    }
 
-        let video_id = video_ids[idx].clone();
-        let hashes = self.hashes.read().unwrap();
-        let stored_hash = *hashes.get(&video_id).unwrap();
-        let hamming_dist = (hash_value ^ stored_hash).count_ones();
+    /// The `k` closest hashes regardless of distance, nearest first with ties
+    /// broken by video_id.
+    pub fn find_nearest_neighbors(
+        &self,
+        hash: &VideoHash,
+        k: usize,
+    ) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
+        let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        Ok(Some((video_id, hamming_dist)))
+        self.snapshot().nearest(hash_value, k)
     }
 
     pub fn find_within_distance(
@@ -139,88 +787,580 @@ impl VideoHashIndex {
     ) -> Result<Vec<(String, u32)>, Box<dyn Error + Send + Sync>> {
         let hash_value = binary_string_to_u64(&hash.hash)?;
 
-        self.ensure_index_built()?;
+        let matches = self.snapshot().within_distance(hash_value, max_distance);
+        observe_distances(&matches);
+        Ok(matches)
+    }
 
-        let index_lock = self.index.read().unwrap();
-        if index_lock.is_none() {
-            return Ok(Vec::new());
+    /// Returns whether the id was removed. During a rebuild the removal is also
+    /// accepted for ids only the new base may hold, as it is replayed onto it.
+    pub fn remove(&self, video_id: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut writer = self.lock_writer()?;
+        let rebasing = writer.rebase_journal.is_some();
+        let removed = self.apply_locked(&mut writer, Mutation::Remove(video_id.to_string()))?;
+
+        Ok(removed || rebasing)
+    }
+
+    /// Inserts or overwrites every hash as one published change, skipping ids that
+    /// already have the same hash. Returns how many were inserted or changed.
+    pub fn upsert_all(
+        &self,
+        video_hashes: &[(String, VideoHash)],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mut writer = self.lock_writer()?;
+        let snapshot = self.snapshot();
+        // A rebuild may not have this hash even if the current snapshot does
+        let rebasing = writer.rebase_journal.is_some();
+        let mut mutations = Vec::new();
+        for (video_id, hash) in video_hashes.iter() {
+            let hash_value = binary_string_to_u64(&hash.hash)?;
+            if rebasing || snapshot.get(video_id) != Some(hash_value) {
+                mutations.push(Mutation::Insert(video_id.clone(), hash_value));
+            }
         }
 
-        let (index, video_ids) = index_lock.as_ref().unwrap();
-        let hashes = self.hashes.read().unwrap();
+        self.apply_batch_locked(&mut writer, mutations)
+    }
 
-        let mut searcher = index.range_searcher();
-        let answers = searcher.run(hash_value, max_distance as usize);
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
 
-        let mut neighbors = Vec::new();
-        for idx in answers {
-            let idx_usize = *idx as usize;
-            if idx_usize < video_ids.len() {
-                let video_id = video_ids[idx_usize].clone();
-                let stored_hash = *hashes.get(&video_id).unwrap();
-                let hamming_dist = (hash_value ^ stored_hash).count_ones();
-                neighbors.push((video_id, hamming_dist));
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replaces the whole index with `video_hashes`, building the new MIH segment
+    /// while the current one keeps serving. Later entries win for duplicate ids.
+    pub fn replace_all(
+        &self,
+        video_hashes: &[(String, VideoHash)],
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let entries = to_entries(video_hashes)?;
+        self.begin_rebuild()?.finish(entries)?;
+
+        Ok(self.len())
+    }
+
+    /// Replaces the whole index with whatever `fetch` returns, without downtime.
+    ///
+    /// The current index keeps serving searches and accepting writes until the new
+    /// one is swapped in. Writes made while `fetch` runs or the new MIH segment is
+    /// built are replayed on top of the fetched contents, so none are lost.
+    pub async fn rebuild_with<F, Fut>(
+        &self,
+        fetch: F,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<(String, VideoHash)>, Box<dyn Error + Send + Sync>>>,
+    {
+        let mut rebuild = self.start_rebuild().await?;
+        rebuild.extend(&fetch().await?)?;
+        rebuild.finish()
+    }
+
+    /// Starts a rebuild whose contents are supplied in batches, for sources too large
+    /// to fetch in one go. Nothing is visible until `PendingRebuild::finish`.
+    pub async fn start_rebuild(&self) -> Result<PendingRebuild<'_>, Box<dyn Error + Send + Sync>> {
+        // Start journaling before the fetch; wait out a background merge if one is running
+        let rebase = loop {
+            if let Some(mut rebase) = self.try_begin_rebase()? {
+                rebase.rebuild = true;
+                break rebase;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+
+        Ok(PendingRebuild {
+            rebase,
+            entries: HashMap::new(),
+        })
+    }
+
+    /// Replaces the whole index with the contents of `source` without blocking
+    /// searches or writers; see `start_rebuild`.
+    pub async fn rebuild_from_source(
+        &self,
+        source: &dyn HashSource,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        log::info!("Starting index rebuild from {}...", source.name());
+        let started = Instant::now();
+        self.update_history(|history| history.rebuild_started = Some(started));
+
+        let result = async {
+            let mut rebuild = self.start_rebuild().await?;
+            let summary = source.load(None, &mut |page| rebuild.extend(&page)).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>((rebuild.finish()?, summary))
+        }
+        .await;
+
+        let (count, summary) = match result {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                self.record_failure("rebuild", source, e.as_ref());
+                self.update_history(|history| history.rebuild_started = None);
+                return Err(e);
             }
+        };
+        if let Some(watermark) = summary.watermark {
+            self.advance_watermark(watermark);
         }
+        record_success("rebuild", started, summary.loaded);
+        self.update_history(|history| {
+            history.rebuild_started = None;
+            history.last_rebuild = Some(LoadRecord {
+                source: source.name(),
+                finished_at: now_rfc3339(),
+                duration_ms: started.elapsed().as_millis() as u64,
+                rows: summary.loaded,
+                indexed: count,
+            });
+        });
+        log::info!("Rebuilt index with {} hashes from {}", count, source.name());
+        Ok(count)
+    }
 
-        neighbors.sort_by_key(|&(_, dist)| dist);
+    /// Upserts the rows created since the last rebuild or sync, keeping everything
+    /// already in the index. Returns how many hashes were inserted or changed.
+    ///
+    /// Without a watermark (nothing ingested yet, or a source that has none) every
+    /// row is upserted.
+    pub async fn sync_from_source(
+        &self,
+        source: &dyn HashSource,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let since = self.watermark();
+        let started = Instant::now();
+        let mut changed = 0;
+        let summary = source
+            .load(since.as_ref(), &mut |page| {
+                changed += self.upsert_all(&page)?;
+                Ok(())
+            })
+            .await
+            .inspect_err(|e| self.record_failure("sync", source, e.as_ref()))?;
+        record_success("sync", started, summary.loaded);
+
+        if let Some(watermark) = summary.watermark {
+            self.advance_watermark(watermark);
+        }
+        Ok(changed)
+    }
 
-        Ok(neighbors)
+    /// The newest BigQuery row ingested so far, if any.
+    pub fn watermark(&self) -> Option<Watermark> {
+        match self.watermark.lock() {
+            Ok(watermark) => watermark.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
     }
 
-    pub fn remove(&self, video_id: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        let mut hashes = self.hashes.write().unwrap();
-        let removed = hashes.remove(video_id).is_some();
+    /// Moves the watermark forward to `watermark`; an older one is ignored.
+    pub fn advance_watermark(&self, watermark: Watermark) {
+        let mut current = match self.watermark.lock() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if current.as_ref() < Some(&watermark) {
+            *current = Some(watermark);
+        }
+    }
 
-        if removed {
-            let mut index = self.index.write().unwrap();
-            *index = None;
+    /// Whether the index has been fully loaded from a snapshot, log or source, so a
+    /// miss really means the video is new.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn mark_ready(&self) {
+        if !self.ready.swap(true, Ordering::AcqRel) {
+            log::info!("Index is ready with {} hashes", self.len());
         }
+    }
 
-        Ok(removed)
+    pub fn status(&self) -> IndexStatus {
+        let snapshot = self.snapshot();
+        let history = match self.history.lock() {
+            Ok(history) => history,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        IndexStatus {
+            ready: self.is_ready(),
+            indexed: snapshot.len(),
+            mih_built: snapshot.base.index.is_some(),
+            mih_size: snapshot.base.codes.len(),
+            pending_changes: snapshot.pending_changes(),
+            rebuild_in_progress: history.rebuild_started.is_some(),
+            swapping: self.swapping.load(Ordering::Acquire),
+            last_rebuild: history.last_rebuild.clone(),
+            last_error: history.last_error.clone(),
+        }
     }
 
-    pub fn len(&self) -> usize {
-        self.hashes.read().unwrap().len()
+    fn update_history(&self, update: impl FnOnce(&mut LoadHistory)) {
+        let mut history = match self.history.lock() {
+            Ok(history) => history,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        update(&mut history);
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    fn record_failure(
+        &self,
+        kind: &str,
+        source: &dyn HashSource,
+        error: &(dyn Error + Send + Sync),
+    ) {
+        metrics::SOURCE_LOADS
+            .with_label_values(&[kind, "failure"])
+            .inc();
+        self.update_history(|history| {
+            history.last_error = Some(LoadError {
+                source: source.name(),
+                failed_at: now_rfc3339(),
+                error: error.to_string(),
+            });
+        });
     }
 
-    pub async fn rebuild_from_bigquery(&self) -> Result<usize, Box<dyn Error + Send + Sync>> {
-        log::info!("Starting index rebuild from BigQuery...");
-        let video_hashes = bigquery::fetch_video_hashes().await?;
+    pub fn needs_rebuild(&self) -> bool {
+        self.is_empty()
+    }
 
-        {
-            let mut hashes = self.hashes.write().unwrap();
-            hashes.clear();
+    /// Counter bumped on every published change, used to skip redundant snapshot writes.
+    pub fn generation(&self) -> u64 {
+        self.snapshot().generation
+    }
+
+    /// Writes the current contents to a snapshot file and returns the generation saved.
+    ///
+    /// With a mutation log attached, the log is rotated at the point the snapshot is
+    /// taken and the segments the snapshot covers are deleted once it is on disk.
+    pub fn save_snapshot(&self, path: &Path) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let _file = self
+            .snapshot_file
+            .lock()
+            .map_err(|_| "Snapshot file lock was poisoned by a panicking writer")?;
+
+        let (snapshot, covered) = {
+            let mut writer = self.lock_writer()?;
+            let covered = match writer.log.as_mut() {
+                Some(log) => Some((log.rotate()?, log.path().to_path_buf())),
+                None => None,
+            };
+            (self.snapshot(), covered)
+        };
+
+        let last_sequence = covered.as_ref().map_or(0, |(sequence, _)| *sequence);
+        persistence::write_snapshot(path, last_sequence, &snapshot.entries())?;
+        if let Some((sequence, log_path)) = covered {
+            wal::remove_segments_through(&log_path, sequence)?;
+        }
+        Ok(snapshot.generation)
+    }
+
+    /// Replaces the index contents with a snapshot file written by `save_snapshot`.
+    pub fn load_snapshot(&self, path: &Path) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let contents = persistence::read_snapshot(path)?;
+        self.begin_rebuild()?.finish(contents.entries)?;
+        Ok(self.len())
+    }
 
-            for (video_id, hash) in video_hashes.iter() {
-                let hash_value = binary_string_to_u64(&hash.hash)?;
-                hashes.insert(video_id.clone(), hash_value);
+    /// Restores the index from the snapshot at `snapshot_path` (if there is one) plus
+    /// the mutation log at `log_path`, then keeps logging every change to that log.
+    ///
+    /// Records the snapshot already covers are skipped. If the log holds a rebuild
+    /// marker after the snapshot, the records following it are handed back instead
+    /// of applied, because they belong on top of freshly reloaded contents.
+    pub fn recover(
+        &self,
+        snapshot_path: Option<&Path>,
+        log_path: &Path,
+        fsync: FsyncPolicy,
+    ) -> Result<Recovery, Box<dyn Error + Send + Sync>> {
+        let mut covered = None;
+        if let Some(path) = snapshot_path.filter(|path| path.exists()) {
+            // A bad snapshot is not fatal: without it the log alone decides whether
+            // a rebuild is needed
+            match persistence::read_snapshot(path) {
+                Ok(contents) => {
+                    self.begin_rebase()?.finish(contents.entries)?;
+                    log::info!(
+                        "Loaded {} hashes from snapshot {:?} (log sequence {})",
+                        self.len(),
+                        path,
+                        contents.last_sequence
+                    );
+                    covered = Some(contents.last_sequence);
+                }
+                Err(e) => log::warn!("Ignoring unreadable index snapshot: {}", e),
             }
+        }
+
+        let contents = wal::read_log(log_path)?;
+        let records: Vec<(u64, WalRecord)> = contents
+            .records
+            .into_iter()
+            .filter(|(sequence, _)| *sequence > covered.unwrap_or(0))
+            .collect();
+
+        let mut recovery = Recovery::default();
+        let first_sequence = records.first().map(|(sequence, _)| *sequence);
+        let last_rebuild = records
+            .iter()
+            .rposition(|(_, record)| *record == WalRecord::Rebuild);
+        if let Some(position) = last_rebuild {
+            recovery.rebuild_required = true;
+            recovery.pending = records[position + 1..]
+                .iter()
+                .map(|(_, record)| record.clone())
+                .collect();
+        } else if first_sequence.is_some_and(|first| first != covered.unwrap_or(0) + 1) {
+            log::warn!(
+                "Mutation log {:?} starts at sequence {} but the snapshot covers up to {}; \
+                 rebuilding before replaying it",
+                log_path,
+                first_sequence.unwrap_or(0),
+                covered.unwrap_or(0)
+            );
+            recovery.rebuild_required = true;
+            recovery.pending = records.into_iter().map(|(_, record)| record).collect();
+        } else {
+            let mutations = records
+                .iter()
+                .filter_map(|(_, record)| Mutation::from_record(record))
+                .collect();
+            self.apply_batch_locked(&mut *self.lock_writer()?, mutations)?;
+            log::info!(
+                "Replayed {} records from mutation log {:?}",
+                records.len(),
+                log_path
+            );
+        }
+
+        let next_sequence = contents.last_sequence.max(covered.unwrap_or(0)) + 1;
+        self.lock_writer()?.log = Some(MutationLog::open(log_path, fsync, next_sequence)?);
+
+        Ok(recovery)
+    }
+
+    /// Applies logged records on top of the current contents, logging them again.
+    pub fn replay(&self, records: &[WalRecord]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mutations = records.iter().filter_map(Mutation::from_record).collect();
+        self.apply_batch_locked(&mut *self.lock_writer()?, mutations)
+    }
+}
+
+/// A rebuild collecting its new contents. The current index keeps serving, and
+/// writes made meanwhile are replayed on top when it finishes. Dropping it abandons
+/// the rebuild.
+pub struct PendingRebuild<'a> {
+    rebase: Rebase<'a>,
+    entries: HashMap<String, u64>,
+}
 
-            let mut index = self.index.write().unwrap();
-            *index = None;
+impl PendingRebuild<'_> {
+    /// Adds a batch of hashes. Later entries win for duplicate ids.
+    pub fn extend(
+        &mut self,
+        video_hashes: &[(String, VideoHash)],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for (video_id, hash) in video_hashes.iter() {
+            let hash_value = binary_string_to_u64(&hash.hash)?;
+            self.entries.insert(video_id.clone(), hash_value);
         }
+        Ok(())
+    }
 
-        self.ensure_index_built()?;
+    /// Number of distinct video_ids collected so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
 
-        let count = self.len();
-        log::info!("Rebuilt index with {} hashes from BigQuery", count);
-        Ok(count)
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
-    pub fn needs_rebuild(&self) -> bool {
-        self.is_empty()
+    /// Builds the new index, replays concurrent writes and swaps it in.
+    /// Returns the number of hashes now indexed.
+    pub fn finish(self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let index = self.rebase.index;
+        self.rebase.finish(self.entries.into_iter().collect())?;
+        Ok(index.len())
+    }
+}
+
+/// Parses hashes into index entries, keeping the last one for duplicate ids.
+fn to_entries(
+    video_hashes: &[(String, VideoHash)],
+) -> Result<Vec<(String, u64)>, Box<dyn Error + Send + Sync>> {
+    let mut entries = HashMap::with_capacity(video_hashes.len());
+    for (video_id, hash) in video_hashes.iter() {
+        let hash_value = binary_string_to_u64(&hash.hash)?;
+        entries.insert(video_id.clone(), hash_value);
+    }
+    Ok(entries.into_iter().collect())
+}
+
+fn record_success(kind: &str, started: Instant, rows: usize) {
+    metrics::SOURCE_LOADS
+        .with_label_values(&[kind, "success"])
+        .inc();
+    metrics::SOURCE_LOAD_DURATION
+        .with_label_values(&[kind])
+        .observe(started.elapsed().as_secs_f64());
+    metrics::SOURCE_ROWS
+        .with_label_values(&[kind])
+        .inc_by(rows as u64);
+}
+
+fn observe_distances(matches: &[(String, u32)]) {
+    for (_, distance) in matches {
+        metrics::MATCH_DISTANCE.observe(f64::from(*distance));
     }
 }
 
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
 pub fn create_shared_index() -> Arc<VideoHashIndex> {
     Arc::new(VideoHashIndex::new())
 }
 
+/// Spawns a thread that periodically merges the delta layer in the background,
+/// keeping MIH rebuilds off the request path.
+pub fn spawn_merge_worker(index: Arc<VideoHashIndex>, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = index.merge_if_needed() {
+            log::error!("Background index merge failed: {}", e);
+        }
+    })
+}
+
+/// Spawns a thread that writes a snapshot to `path` every `interval` if the index
+/// changed since the last save.
+pub fn spawn_snapshot_worker(
+    index: Arc<VideoHashIndex>,
+    path: PathBuf,
+    interval: Duration,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut saved_generation = None;
+        loop {
+            thread::sleep(interval);
+            if saved_generation == Some(index.generation()) {
+                continue;
+            }
+
+            let started = Instant::now();
+            match index.save_snapshot(&path) {
+                Ok(generation) => {
+                    saved_generation = Some(generation);
+                    log::info!(
+                        "Saved index snapshot to {:?} in {:?}",
+                        path,
+                        started.elapsed()
+                    );
+                }
+                Err(e) => log::error!("Failed to save index snapshot: {}", e),
+            }
+        }
+    })
+}
+
+/// Spawns a task that loads the index from `source`, backing off between failed
+/// attempts, then replays `pending` on top and marks the index ready. Searches keep
+/// being served from whatever is loaded meanwhile.
+pub fn spawn_initial_load(
+    index: Arc<VideoHashIndex>,
+    source: Arc<dyn HashSource>,
+    pending: Vec<WalRecord>,
+    retry: RetryPolicy,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut attempt = 1;
+        loop {
+            match index.rebuild_from_source(source.as_ref()).await {
+                Ok(_) => break,
+                Err(e) if attempt >= retry.max_attempts => {
+                    log::error!(
+                        "Giving up on loading the index from {} after {} attempts: {}; \
+                         it stays not ready until a rebuild succeeds",
+                        source.name(),
+                        attempt,
+                        e
+                    );
+                    return;
+                }
+                Err(e) => {
+                    let delay = retry.backoff(attempt);
+                    log::error!(
+                        "Loading the index from {} failed (attempt {}): {}; retrying in {:?}",
+                        source.name(),
+                        attempt,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            match index.replay(&pending) {
+                Ok(count) => log::info!(
+                    "Replayed {} logged changes made after the last rebuild",
+                    count
+                ),
+                Err(e) => log::error!("Could not replay logged changes: {}", e),
+            }
+        }
+        index.mark_ready();
+    })
+}
+
+/// Spawns a task that pulls newly created rows from `source` into the index every
+/// `interval`, saving the watermark to `state_path` whenever it moves.
+pub fn spawn_sync_worker(
+    index: Arc<VideoHashIndex>,
+    source: Arc<dyn HashSource>,
+    interval: Duration,
+    state_path: Option<PathBuf>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut saved = index.watermark();
+        loop {
+            tokio::time::sleep(interval).await;
+            // Until the first load lands there is no watermark, so a sync would
+            // fetch the whole table a second time
+            if !index.is_ready() {
+                continue;
+            }
+
+            match index.sync_from_source(source.as_ref()).await {
+                Ok(0) => {}
+                Ok(changed) => log::info!("Synced {} new hashes from {}", changed, source.name()),
+                Err(e) => log::error!("Incremental sync from {} failed: {}", source.name(), e),
+            }
+
+            let watermark = index.watermark();
+            if let (Some(path), Some(current)) = (&state_path, &watermark) {
+                if saved.as_ref() != Some(current) {
+                    match persistence::write_json(path, current) {
+                        Ok(()) => saved = watermark,
+                        Err(e) => log::error!("Failed to save sync watermark: {}", e),
+                    }
+                }
+            }
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,4 +1458,439 @@ mod tests {
 
         Ok(())
     }
+
+    fn brute_force(
+        expected: &HashMap<String, u64>,
+        query: u64,
+        max_distance: u32,
+    ) -> Vec<(String, u32)> {
+        let mut neighbors: Vec<(String, u32)> = expected
+            .iter()
+            .map(|(id, &code)| (id.clone(), (query ^ code).count_ones()))
+            .filter(|&(_, dist)| dist <= max_distance)
+            .collect();
+        neighbors.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        neighbors
+    }
+
+    fn u64_to_hash(code: u64) -> VideoHash {
+        VideoHash {
+            hash: format!("{:064b}", code),
+        }
+    }
+
+    #[test]
+    fn test_delta_matches_full_rebuild() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        let mut expected: HashMap<String, u64> = HashMap::new();
+
+        // Deterministic xorshift so codes cluster around a few seeds
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let seeds = [0u64, u64::MAX, 0xAAAAAAAAAAAAAAAA];
+
+        for round in 0..6 {
+            for i in 0..50 {
+                let video_id = format!("video-{}", next() % 120);
+                let code =
+                    seeds[i % seeds.len()] ^ (1u64 << (next() % 64)) ^ (1u64 << (next() % 64));
+                index.add(video_id.clone(), &u64_to_hash(code))?;
+                expected.insert(video_id, code);
+            }
+            for _ in 0..10 {
+                let video_id = format!("video-{}", next() % 120);
+                assert_eq!(
+                    index.remove(&video_id)?,
+                    expected.remove(&video_id).is_some()
+                );
+            }
+
+            for &seed in seeds.iter() {
+                for max_distance in [0, 1, 2, 4] {
+                    assert_eq!(
+                        index.find_within_distance(&u64_to_hash(seed), max_distance)?,
+                        brute_force(&expected, seed, max_distance)
+                    );
+                }
+                let nearest = index.find_nearest_neighbor(&u64_to_hash(seed))?;
+                assert_eq!(nearest, brute_force(&expected, seed, 64).into_iter().next());
+                for k in [3, 10, 200] {
+                    let mut top_k = brute_force(&expected, seed, 64);
+                    top_k.truncate(k);
+                    assert_eq!(index.find_nearest_neighbors(&u64_to_hash(seed), k)?, top_k);
+                }
+            }
+
+            // Merge on alternate rounds so searches cover base, delta and tombstones together
+            if round % 2 == 0 {
+                index.merge_delta()?;
+            }
+        }
+
+        assert_eq!(index.len(), expected.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nearest_skips_tombstones_next_to_the_query(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        let mut expected = HashMap::new();
+        for bits in 0..64u32 {
+            let code = u64::MAX >> bits;
+            index.add(format!("video-{:02}", bits), &u64_to_hash(code))?;
+            expected.insert(format!("video-{:02}", bits), code);
+        }
+        index.merge_delta()?;
+
+        // The hashes nearest the query are all deleted, so the search must widen
+        for bits in 0..40 {
+            index.remove(&format!("video-{:02}", bits))?;
+            expected.remove(&format!("video-{:02}", bits));
+        }
+        for k in [1, 3, 10] {
+            let mut top_k = brute_force(&expected, u64::MAX, 64);
+            top_k.truncate(k);
+            assert_eq!(
+                index.find_nearest_neighbors(&u64_to_hash(u64::MAX), k)?,
+                top_k
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_hides_merged_hash() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        index.merge_delta()?;
+
+        index.add("video-001".to_string(), &u64_to_hash(u64::MAX))?;
+        assert!(index.find_within_distance(&u64_to_hash(0), 1)?.is_empty());
+        assert_eq!(
+            index.find_within_distance(&u64_to_hash(u64::MAX), 1)?,
+            vec![("video-001".to_string(), 0)]
+        );
+
+        assert!(index.remove("video-001")?);
+        assert!(index.find_nearest_neighbor(&u64_to_hash(0))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_if_needed_respects_policy() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let index = VideoHashIndex::with_merge_policy(MergePolicy {
+            max_delta: 2,
+            max_age: Duration::from_secs(3600),
+        });
+
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        assert!(!index.merge_if_needed()?);
+
+        index.add("video-002".to_string(), &u64_to_hash(1))?;
+        assert!(index.merge_if_needed()?);
+        assert!(!index.merge_if_needed()?);
+
+        assert_eq!(index.find_within_distance(&u64_to_hash(0), 1)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_searches_do_not_block_on_writers(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use std::sync::Barrier;
+
+        const READERS: usize = 4;
+
+        let index = Arc::new(VideoHashIndex::new());
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+
+        let barrier = Arc::new(Barrier::new(READERS + 1));
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let index = index.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let snapshot = index.snapshot();
+                    // Every reader holds its view at once, then the writer publishes
+                    barrier.wait();
+                    barrier.wait();
+                    snapshot.within_distance(0, 0)
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        index.add("video-002".to_string(), &u64_to_hash(0))?;
+        index.merge_delta()?;
+        barrier.wait();
+
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), vec![("video-001".to_string(), 0)]);
+        }
+        assert_eq!(index.find_within_distance(&u64_to_hash(0), 0)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_identical_submissions_insert_once(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use std::sync::Barrier;
+
+        const SUBMITTERS: usize = 16;
+
+        for round in 0..20u64 {
+            let index = Arc::new(VideoHashIndex::new());
+            let barrier = Arc::new(Barrier::new(SUBMITTERS));
+            let submitters: Vec<_> = (0..SUBMITTERS)
+                .map(|i| {
+                    let index = index.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        // Every submission is within distance 1 of the others' hash
+                        let code = round ^ ((i as u64 % 2) << 63);
+                        index.search_or_insert(format!("video-{}", i), &u64_to_hash(code), 1)
+                    })
+                })
+                .collect();
+
+            let mut inserted = 0;
+            for submitter in submitters {
+                match submitter.join().unwrap()? {
+                    SearchOutcome::Inserted => inserted += 1,
+                    SearchOutcome::Matches(matches) => assert_eq!(matches.len(), 1),
+                    SearchOutcome::ExactMatch => panic!("distinct video_ids cannot match exactly"),
+                    SearchOutcome::NoMatch => panic!("search_or_insert never reports NoMatch"),
+                }
+            }
+
+            assert_eq!(inserted, 1);
+            assert_eq!(index.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_or_insert_exact_match() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+
+        assert_eq!(
+            index.search_or_insert("video-001".to_string(), &u64_to_hash(0), 1)?,
+            SearchOutcome::Inserted
+        );
+        assert_eq!(
+            index.search_or_insert("video-001".to_string(), &u64_to_hash(0), 1)?,
+            SearchOutcome::ExactMatch
+        );
+        assert_eq!(
+            index.search_or_insert("video-002".to_string(), &u64_to_hash(1), 1)?,
+            SearchOutcome::Matches(vec![("video-001".to_string(), 1)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_existing_never_inserts() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let index = VideoHashIndex::new();
+
+        assert_eq!(
+            index.search_existing("video-001", &u64_to_hash(0), 1)?,
+            SearchOutcome::NoMatch
+        );
+        assert!(index.is_empty());
+
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        assert_eq!(
+            index.search_existing("video-001", &u64_to_hash(0), 1)?,
+            SearchOutcome::ExactMatch
+        );
+        assert_eq!(
+            index.search_existing("video-002", &u64_to_hash(1), 1)?,
+            SearchOutcome::Matches(vec![("video-001".to_string(), 1)])
+        );
+        assert_eq!(index.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_restores_index() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path =
+            std::env::temp_dir().join(format!("videohash-index-{}.snapshot", std::process::id()));
+
+        let index = VideoHashIndex::new();
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        index.merge_delta()?;
+        index.add("video-002".to_string(), &u64_to_hash(1))?;
+        index.remove("video-001")?;
+        index.add("video-003".to_string(), &u64_to_hash(u64::MAX))?;
+        index.save_snapshot(&path)?;
+
+        let restored = VideoHashIndex::new();
+        assert_eq!(restored.load_snapshot(&path)?, 2);
+        assert_eq!(
+            restored.find_within_distance(&u64_to_hash(0), 1)?,
+            vec![("video-002".to_string(), 1)]
+        );
+        assert!(restored.has_exact_match("video-003", &u64_to_hash(u64::MAX))?);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_replays_log_after_snapshot(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let dir = std::env::temp_dir().join(format!("videohash-recover-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let snapshot_path = dir.join("index.snapshot");
+        let log_path = dir.join("index.wal");
+
+        let index = VideoHashIndex::new();
+        index.recover(Some(&snapshot_path), &log_path, FsyncPolicy::Never)?;
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        index.add("video-002".to_string(), &u64_to_hash(1))?;
+        index.save_snapshot(&snapshot_path)?;
+        index.add("video-003".to_string(), &u64_to_hash(u64::MAX))?;
+        index.remove("video-001")?;
+        drop(index);
+
+        let restored = VideoHashIndex::new();
+        let recovery = restored.recover(Some(&snapshot_path), &log_path, FsyncPolicy::Never)?;
+        assert!(!recovery.rebuild_required);
+        assert_eq!(restored.len(), 2);
+        assert!(restored.has_exact_match("video-003", &u64_to_hash(u64::MAX))?);
+        assert!(!restored.has_exact_match("video-001", &u64_to_hash(0))?);
+
+        // Changes after a rebuild are handed back to be replayed on reloaded contents
+        restored.replace_all(&[("video-010".to_string(), u64_to_hash(7))])?;
+        restored.add("video-011".to_string(), &u64_to_hash(8))?;
+        drop(restored);
+
+        let rebuilt = VideoHashIndex::new();
+        let recovery = rebuilt.recover(Some(&snapshot_path), &log_path, FsyncPolicy::Never)?;
+        assert!(recovery.rebuild_required);
+        assert_eq!(
+            recovery.pending,
+            vec![WalRecord::Insert {
+                video_id: "video-011".to_string(),
+                hash: 8,
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_keeps_serving_and_keeps_live_writes(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        index.add("video-old".to_string(), &u64_to_hash(0))?;
+
+        let count = index
+            .rebuild_with(|| async {
+                // While the fetch runs the old contents still answer searches
+                assert_eq!(index.find_within_distance(&u64_to_hash(0), 0)?.len(), 1);
+                index.add("video-live".to_string(), &u64_to_hash(u64::MAX))?;
+                Ok(vec![("video-fetched".to_string(), u64_to_hash(1))])
+            })
+            .await?;
+
+        assert_eq!(count, 2);
+        assert!(!index.has_exact_match("video-old", &u64_to_hash(0))?);
+        assert!(index.has_exact_match("video-fetched", &u64_to_hash(1))?);
+        assert!(index.has_exact_match("video-live", &u64_to_hash(u64::MAX))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writes_during_rebuild_apply_to_the_rebuilt_contents(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        index.add("video-kept".to_string(), &u64_to_hash(5))?;
+
+        index
+            .rebuild_with(|| async {
+                // Only the incoming data has this id; the removal still counts
+                assert!(index.remove("video-fetched")?);
+                // Already indexed with this hash, but missing from the incoming data
+                index.upsert_all(&[("video-kept".to_string(), u64_to_hash(5))])?;
+                Ok(vec![("video-fetched".to_string(), u64_to_hash(1))])
+            })
+            .await?;
+
+        assert!(!index.has_exact_match("video-fetched", &u64_to_hash(1))?);
+        assert!(index.has_exact_match("video-kept", &u64_to_hash(5))?);
+        assert_eq!(index.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert_all_skips_unchanged_hashes(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = VideoHashIndex::new();
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        index.add("video-002".to_string(), &u64_to_hash(1))?;
+        let generation = index.generation();
+
+        let upserts = vec![
+            ("video-001".to_string(), u64_to_hash(0)),
+            ("video-002".to_string(), u64_to_hash(u64::MAX)),
+            ("video-003".to_string(), u64_to_hash(7)),
+        ];
+        assert_eq!(index.upsert_all(&upserts)?, 2);
+        // The whole batch is published as a single change
+        assert_eq!(index.generation(), generation + 1);
+        assert!(index.has_exact_match("video-002", &u64_to_hash(u64::MAX))?);
+        assert!(index.has_exact_match("video-003", &u64_to_hash(7))?);
+
+        assert_eq!(index.upsert_all(&upserts)?, 0);
+        assert_eq!(index.generation(), generation + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watermark_only_moves_forward() {
+        let index = VideoHashIndex::new();
+        let watermark = |created_at_micros, video_id: &str| Watermark {
+            created_at_micros,
+            video_id: video_id.to_string(),
+        };
+
+        index.advance_watermark(watermark(10, "video-b"));
+        index.advance_watermark(watermark(10, "video-a"));
+        index.advance_watermark(watermark(5, "video-z"));
+        assert_eq!(index.watermark(), Some(watermark(10, "video-b")));
+
+        index.advance_watermark(watermark(11, "video-a"));
+        assert_eq!(index.watermark(), Some(watermark(11, "video-a")));
+    }
+
+    #[test]
+    fn test_poisoned_writer_lock_is_recoverable(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = Arc::new(VideoHashIndex::new());
+
+        let poisoner = index.clone();
+        let _ = thread::spawn(move || {
+            let _writer = poisoner.writer.lock().unwrap();
+            panic!("writer panicked while holding the lock");
+        })
+        .join();
+
+        assert!(index.add("video-001".to_string(), &u64_to_hash(0)).is_err());
+        index.add("video-001".to_string(), &u64_to_hash(0))?;
+        assert_eq!(index.find_within_distance(&u64_to_hash(0), 0)?.len(), 1);
+        Ok(())
+    }
 }
@@ -1,25 +1,64 @@
+pub mod auth;
 pub mod bigquery;
+pub mod bigquery_api;
+pub mod config;
+pub mod decisions;
 pub mod index;
+pub mod limits;
+pub mod metrics;
+pub mod persistence;
+pub mod policy;
+pub mod retry;
+pub mod source;
+mod sync;
 pub mod videohash;
-pub use index::{create_shared_index, VideoHashIndex};
+pub mod wal;
+pub mod writeback;
+pub use auth::{ApiKey, ApiKeys, Scope};
+pub use bigquery::BigQuerySource;
+pub use config::Config;
+pub use decisions::DecisionLog;
+pub use index::{create_shared_index, IndexStatus, SearchOutcome, VideoHashIndex};
+pub use limits::{ConcurrencyLimits, RateLimiter};
+pub use policy::{SearchPolicy, SearchPolicyStore, SearchPolicyUpdate};
+pub use source::HashSource;
 pub use videohash::VideoHash;
+pub use writeback::WriteBack;
 
-use actix_web::{web, HttpResponse};
+use actix_web::{middleware, web, HttpMessage, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 #[derive(Serialize)]
 pub struct VideoMatch {
     pub video_id: String,
+    pub hamming_distance: u32,
     pub similarity_percentage: f64,
     pub is_duplicate: bool,
 }
 
+impl VideoMatch {
+    fn new((video_id, distance): (String, u32), max_distance: u32) -> Self {
+        Self {
+            video_id,
+            hamming_distance: distance,
+            similarity_percentage: 100.0 * (64.0 - distance as f64) / 64.0,
+            is_duplicate: distance <= max_distance,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct SearchResponse {
     pub match_found: bool,
     pub match_details: Option<VideoMatch>,
     pub hash_added: bool,
+    /// Only present for `insert: false` requests: whether the hash would have been added.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub would_add: Option<bool>,
+    /// Every hash within the threshold, nearest first, when the request set `return_all`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Vec<VideoMatch>>,
 }
 
 #[derive(Serialize)]
@@ -27,17 +66,80 @@ pub struct ErrorResponse {
     pub error: String,
 }
 
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub indexed: usize,
+}
+
+/// Registered as app data to answer `/search` with a 503 until the index is ready,
+/// rather than inserting every upload into a half-loaded index.
+#[derive(Clone, Copy, Debug)]
+pub struct RequireReady;
+
+/// Seconds a client turned away during loading is asked to wait.
+const NOT_READY_RETRY_AFTER: &str = "5";
+
 #[derive(Deserialize, Serialize)]
 pub struct SearchRequest {
     pub video_id: String,
     pub hash: String,
+    /// Insert the hash when nothing matches. `false` makes the search read-only.
+    #[serde(default = "default_insert")]
+    pub insert: bool,
+    /// Hamming distance threshold, capped by the `SearchPolicy`.
+    #[serde(default)]
+    pub max_distance: Option<u32>,
+    /// Return every match rather than only the nearest.
+    #[serde(default)]
+    pub return_all: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NeighborsRequest {
+    pub hash: String,
+    /// Number of neighbours to return, capped at `MAX_NEIGHBORS`.
+    #[serde(default = "default_k")]
+    pub k: usize,
+    /// Threshold for `is_duplicate` on each neighbour; nothing is filtered by it.
+    #[serde(default)]
+    pub max_distance: Option<u32>,
+}
+
+fn default_k() -> usize {
+    10
+}
+
+#[derive(Serialize)]
+pub struct NeighborsResponse {
+    pub neighbors: Vec<VideoMatch>,
+}
+
+const MAX_NEIGHBORS: usize = 100;
+
+fn default_insert() -> bool {
+    true
 }
 
 pub async fn search(
     req: web::Json<SearchRequest>,
     index: web::Data<Arc<VideoHashIndex>>,
+    write_back: Option<web::Data<WriteBack>>,
+    decision_log: Option<web::Data<DecisionLog>>,
+    require_ready: Option<web::Data<RequireReady>>,
+    search_policy: Option<web::Data<SearchPolicyStore>>,
 ) -> HttpResponse {
-    const MAX_HAMMING_DISTANCE: u32 = 1;
+    if require_ready.is_some() && !index.is_ready() {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", NOT_READY_RETRY_AFTER))
+            .json(ErrorResponse {
+                error: "The index is still loading; retry shortly".to_string(),
+            });
+    }
+
+    let policy = current_policy(search_policy);
+    let max_distance = policy.max_distance(req.max_distance);
+    let insert = policy.should_insert(req.insert);
 
     let query_hash = match VideoHash::from_binary_string(&req.hash) {
         Ok(hash) => hash,
@@ -48,82 +150,291 @@ pub async fn search(
         }
     };
 
-    let has_exact_match = match index.has_exact_match(&req.video_id, &query_hash) {
-        Ok(exists) => exists,
+    let result = if insert {
+        index.search_or_insert(req.video_id.clone(), &query_hash, max_distance)
+    } else {
+        index.search_existing(&req.video_id, &query_hash, max_distance)
+    };
+    let outcome = match result {
+        Ok(outcome) => outcome,
         Err(e) => {
             return HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Failed to check for exact match: {}", e),
+                error: format!("Search failed: {}", e),
             });
         }
     };
 
-    if has_exact_match {
-        return HttpResponse::Ok().json(SearchResponse {
-            match_found: false,
-            match_details: None,
-            hash_added: false,
-        });
+    // Read-only requests report whether the hash would have been inserted
+    let would_add = |added: bool| if insert { None } else { Some(added) };
+    let all_matches = |matches: Vec<(String, u32)>| {
+        req.return_all.then(|| {
+            matches
+                .into_iter()
+                .map(|found| VideoMatch::new(found, max_distance))
+                .collect()
+        })
+    };
+
+    match outcome {
+        SearchOutcome::ExactMatch => {
+            metrics::SEARCH_OUTCOMES
+                .with_label_values(&["exact_match"])
+                .inc();
+            HttpResponse::Ok().json(SearchResponse {
+                match_found: false,
+                match_details: None,
+                hash_added: false,
+                would_add: would_add(false),
+                matches: all_matches(Vec::new()),
+            })
+        }
+        SearchOutcome::Matches(similar_hashes) => {
+            metrics::SEARCH_OUTCOMES.with_label_values(&["match"]).inc();
+            if let Some(decision_log) = &decision_log {
+                decision_log.record(decisions::DecisionEvent::new(
+                    &req.video_id,
+                    &similar_hashes[0],
+                    max_distance,
+                    !insert,
+                ));
+            }
+            let response = SearchResponse {
+                match_found: true,
+                match_details: Some(VideoMatch::new(similar_hashes[0].clone(), max_distance)),
+                hash_added: false,
+                would_add: would_add(false),
+                matches: all_matches(similar_hashes),
+            };
+
+            HttpResponse::Ok().json(response)
+        }
+        SearchOutcome::Inserted => {
+            metrics::SEARCH_OUTCOMES
+                .with_label_values(&["inserted"])
+                .inc();
+            if let Some(write_back) = &write_back {
+                write_back.record_added(&req.video_id, &query_hash.hash);
+            }
+            HttpResponse::Ok().json(SearchResponse {
+                match_found: false,
+                match_details: None,
+                hash_added: true,
+                would_add: None,
+                matches: all_matches(Vec::new()),
+            })
+        }
+        SearchOutcome::NoMatch => {
+            metrics::SEARCH_OUTCOMES
+                .with_label_values(&["no_match"])
+                .inc();
+            HttpResponse::Ok().json(SearchResponse {
+                match_found: false,
+                match_details: None,
+                hash_added: false,
+                would_add: would_add(true),
+                matches: all_matches(Vec::new()),
+            })
+        }
     }
+}
 
-    // Continue with the existing similarity search logic
-    let similar_hashes = match index.find_within_distance(&query_hash, MAX_HAMMING_DISTANCE) {
-        Ok(results) => results,
+/// Returns the `k` closest videos whether or not they count as duplicates.
+pub async fn neighbors(
+    req: web::Json<NeighborsRequest>,
+    index: web::Data<Arc<VideoHashIndex>>,
+    search_policy: Option<web::Data<SearchPolicyStore>>,
+) -> HttpResponse {
+    let query_hash = match VideoHash::from_binary_string(&req.hash) {
+        Ok(hash) => hash,
         Err(e) => {
-            return HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Search failed: {}", e),
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("Invalid hash format: {}", e),
             });
         }
     };
+    let max_distance = current_policy(search_policy).max_distance(req.max_distance);
 
-    if !similar_hashes.is_empty() {
-        let (video_id, distance) = similar_hashes[0].clone();
-        let similarity = 100.0 * (64.0 - distance as f64) / 64.0;
-
-        let response = SearchResponse {
-            match_found: true,
-            match_details: Some(VideoMatch {
-                video_id,
-                similarity_percentage: similarity,
-                is_duplicate: true,
-            }),
-            hash_added: false,
-        };
+    match index.find_nearest_neighbors(&query_hash, req.k.min(MAX_NEIGHBORS)) {
+        Ok(found) => HttpResponse::Ok().json(NeighborsResponse {
+            neighbors: found
+                .into_iter()
+                .map(|found| VideoMatch::new(found, max_distance))
+                .collect(),
+        }),
+        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
+            error: format!("Neighbor search failed: {}", e),
+        }),
+    }
+}
 
-        HttpResponse::Ok().json(response)
-    } else {
-        match index.add(req.video_id.clone(), &query_hash) {
-            Ok(_) => {
-                let response = SearchResponse {
-                    match_found: false,
-                    match_details: None,
-                    hash_added: true,
-                };
-
-                HttpResponse::Ok().json(response)
-            }
-            Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Failed to add hash: {}", e),
-            }),
-        }
+/// Registers every route together with the shared index and the source `/rebuild`
+/// reads from. The binary mounts exactly this, so anything built on it behaves like
+/// production. Optional services such as `WriteBack`, `DecisionLog`, the
+/// `SearchPolicyStore`, the `ApiKeys` and the limiters are picked up from app data
+/// registered next to it; without a store the default `SearchPolicy` applies.
+///
+/// Middleware registered last runs first: a request is authenticated, then rate
+/// limited by client, then admitted by the concurrency limit.
+pub fn configure(
+    index: Arc<VideoHashIndex>,
+    source: Arc<dyn HashSource>,
+) -> impl Fn(&mut web::ServiceConfig) + Clone {
+    move |cfg| {
+        cfg.app_data(web::Data::new(index.clone()))
+            .app_data(web::Data::new(source.clone()))
+            .service(
+                web::scope("")
+                    .wrap(middleware::from_fn(metrics::track_requests))
+                    .service(
+                        web::resource("/search")
+                            .wrap(middleware::from_fn(limits::limit_searches))
+                            .wrap(middleware::from_fn(limits::rate_limit))
+                            .wrap(middleware::from_fn(auth::require_search))
+                            .route(web::post().to(search)),
+                    )
+                    .service(
+                        web::resource("/neighbors")
+                            .wrap(middleware::from_fn(limits::limit_searches))
+                            .wrap(middleware::from_fn(limits::rate_limit))
+                            .wrap(middleware::from_fn(auth::require_search))
+                            .route(web::post().to(neighbors)),
+                    )
+                    .service(
+                        web::resource("/hash/{video_id}")
+                            .wrap(middleware::from_fn(limits::rate_limit))
+                            .wrap(middleware::from_fn(auth::require_admin))
+                            .route(web::delete().to(delete_hash)),
+                    )
+                    .service(
+                        web::resource("/rebuild")
+                            .wrap(middleware::from_fn(limits::limit_rebuilds))
+                            .wrap(middleware::from_fn(limits::rate_limit))
+                            .wrap(middleware::from_fn(auth::require_admin))
+                            .route(web::post().to(rebuild_index)),
+                    )
+                    .service(
+                        web::resource("/admin/search-policy")
+                            .wrap(middleware::from_fn(limits::rate_limit))
+                            .wrap(middleware::from_fn(auth::require_admin))
+                            .route(web::get().to(get_search_policy))
+                            .route(web::patch().to(update_search_policy)),
+                    )
+                    .route("/healthz", web::get().to(health))
+                    .route("/readyz", web::get().to(readiness))
+                    .service(
+                        web::resource("/status")
+                            .wrap(middleware::from_fn(auth::require_admin))
+                            .route(web::get().to(status)),
+                    )
+                    .route("/metrics", web::get().to(metrics::metrics)),
+            );
     }
 }
 
 pub async fn delete_hash(
     path: web::Path<String>,
     index: web::Data<Arc<VideoHashIndex>>,
+    write_back: Option<web::Data<WriteBack>>,
 ) -> HttpResponse {
     let video_id = path.into_inner();
 
     match index.remove(&video_id) {
-        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": format!("Hash with video_id {} successfully deleted", video_id)
-        })),
-        Ok(false) => HttpResponse::NotFound().json(ErrorResponse {
-            error: format!("Hash with video_id {} not found", video_id),
-        }),
+        Ok(true) => {
+            metrics::DELETES.with_label_values(&["deleted"]).inc();
+            if let Some(write_back) = &write_back {
+                write_back.record_removed(&video_id);
+            }
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": format!("Hash with video_id {} successfully deleted", video_id)
+            }))
+        }
+        Ok(false) => {
+            metrics::DELETES.with_label_values(&["not_found"]).inc();
+            HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("Hash with video_id {} not found", video_id),
+            })
+        }
         Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
             error: format!("Failed to remove hash: {}", e),
         }),
     }
 }
+
+pub async fn rebuild_index(
+    index: web::Data<Arc<VideoHashIndex>>,
+    source: web::Data<Arc<dyn HashSource>>,
+) -> HttpResponse {
+    match index.rebuild_from_source(source.as_ref().as_ref()).await {
+        Ok(count) => {
+            index.mark_ready();
+            HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "message": format!("Index rebuilt successfully with {} video hashes", count)
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
+            error: format!("Failed to rebuild index: {}", e),
+        }),
+    }
+}
+
+/// Liveness: the process is up and answering requests.
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// 200 once the index is loaded, 503 while it is still loading. Rebuilds do not
+/// count: the current index keeps answering until the new one is swapped in.
+pub async fn readiness(index: web::Data<Arc<VideoHashIndex>>) -> HttpResponse {
+    let response = ReadinessResponse {
+        ready: index.is_ready(),
+        indexed: index.len(),
+    };
+    if response.ready {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    }
+}
+
+pub async fn status(index: web::Data<Arc<VideoHashIndex>>) -> HttpResponse {
+    HttpResponse::Ok().json(index.status())
+}
+
+fn current_policy(store: Option<web::Data<SearchPolicyStore>>) -> SearchPolicy {
+    store.map_or_else(SearchPolicy::default, |store| store.current())
+}
+
+pub async fn get_search_policy(
+    search_policy: Option<web::Data<SearchPolicyStore>>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(current_policy(search_policy))
+}
+
+/// Changes the fields present in the body and leaves the others alone. The new
+/// policy applies to every request that starts after this one returns.
+pub async fn update_search_policy(
+    req: HttpRequest,
+    update: web::Json<SearchPolicyUpdate>,
+    search_policy: Option<web::Data<SearchPolicyStore>>,
+) -> HttpResponse {
+    let store = match search_policy {
+        Some(store) => store,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: "This server has no runtime search policy to change".to_string(),
+            })
+        }
+    };
+    let origin = match req.extensions().get::<auth::ClientName>() {
+        Some(client) => format!("PATCH /admin/search-policy from '{}'", client.0),
+        None => "PATCH /admin/search-policy".to_string(),
+    };
+    match store.update(&update, &origin) {
+        Ok(policy) => HttpResponse::Ok().json(policy),
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("Invalid search policy: {}", e),
+        }),
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse};
+use tokio::sync::Semaphore;
+
+use crate::auth::ClientName;
+use crate::writeback::parse_var;
+use crate::{metrics, ErrorResponse};
+
+/// Buckets kept at most, so one-off clients do not pile up. Reaching it drops
+/// the least recently used buckets down to `TRACKED_CLIENTS_AFTER_EVICTION`,
+/// leaving room for many new clients before the next sweep.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+const TRACKED_CLIENTS_AFTER_EVICTION: usize = MAX_TRACKED_CLIENTS / 10 * 9;
+
+/// Seconds a client turned away by a concurrency limit is asked to wait.
+const OVERLOADED_RETRY_AFTER: &str = "1";
+
+/// Per-client token bucket: `per_sec` requests a second on average, with bursts
+/// of up to `burst`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitsConfig {
+    /// `None` leaves clients unlimited.
+    pub rate: Option<RateLimit>,
+    /// Header holding the client address set by the proxy in front, such as
+    /// `Fly-Client-IP`. Without it clients without a key are told apart by the
+    /// address of the connection.
+    pub client_ip_header: Option<String>,
+    /// Searches and neighbour lookups answered at once; the rest get a 503.
+    pub max_concurrent_searches: usize,
+    pub max_concurrent_rebuilds: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            client_ip_header: None,
+            max_concurrent_searches: 256,
+            max_concurrent_rebuilds: 1,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Reads `RATE_LIMIT_*`, `SEARCH_MAX_CONCURRENT` and `REBUILD_MAX_CONCURRENT`.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let defaults = Self::default();
+        let rate = match lookup("RATE_LIMIT_PER_SEC") {
+            Some(_) => {
+                let per_sec: f64 = parse_var(&lookup, "RATE_LIMIT_PER_SEC", 0.0)?;
+                if !per_sec.is_finite() || per_sec <= 0.0 {
+                    return Err(format!(
+                        "RATE_LIMIT_PER_SEC must be a positive number, got {}",
+                        per_sec
+                    )
+                    .into());
+                }
+                let burst = parse_var(&lookup, "RATE_LIMIT_BURST", per_sec.ceil() as u32)?;
+                if burst == 0 {
+                    return Err("RATE_LIMIT_BURST must be at least 1".into());
+                }
+                Some(RateLimit { per_sec, burst })
+            }
+            None => None,
+        };
+        let config = Self {
+            rate,
+            client_ip_header: lookup("RATE_LIMIT_CLIENT_IP_HEADER"),
+            max_concurrent_searches: parse_var(
+                &lookup,
+                "SEARCH_MAX_CONCURRENT",
+                defaults.max_concurrent_searches,
+            )?,
+            max_concurrent_rebuilds: parse_var(
+                &lookup,
+                "REBUILD_MAX_CONCURRENT",
+                defaults.max_concurrent_rebuilds,
+            )?,
+        };
+        if config.max_concurrent_searches == 0 || config.max_concurrent_rebuilds == 0 {
+            return Err(
+                "SEARCH_MAX_CONCURRENT and REBUILD_MAX_CONCURRENT must be at least 1".into(),
+            );
+        }
+        Ok(config)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by client: the API key name, or the address for requests
+/// without a key.
+pub struct RateLimiter {
+    limit: RateLimit,
+    client_ip_header: Option<String>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, client_ip_header: Option<String>) -> Self {
+        Self {
+            limit,
+            client_ip_header,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or says how long until one is back.
+    pub fn acquire(&self, client: &str) -> Result<(), Duration> {
+        self.acquire_at(client, Instant::now())
+    }
+
+    fn acquire_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let RateLimit { per_sec, burst } = self.limit;
+        let burst = f64::from(burst);
+        // A bucket is refilled before it is read, so a poisoned one is still sound
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            evict_least_recent(&mut buckets, TRACKED_CLIENTS_AFTER_EVICTION);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    pub(crate) fn client(&self, req: &ServiceRequest) -> String {
+        if let Some(ClientName(name)) = req.extensions().get::<ClientName>() {
+            return format!("key:{}", name);
+        }
+        let forwarded = self.client_ip_header.as_ref().and_then(|header| {
+            req.headers()
+                .get(header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        });
+        let address = forwarded
+            .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+            .unwrap_or_default();
+        format!("ip:{}", address)
+    }
+}
+
+/// Drops the buckets updated longest ago until `keep` are left.
+fn evict_least_recent(buckets: &mut HashMap<String, Bucket>, keep: usize) {
+    if buckets.len() <= keep {
+        return;
+    }
+    let mut ages: Vec<(Instant, String)> = buckets
+        .iter()
+        .map(|(client, bucket)| (bucket.updated, client.clone()))
+        .collect();
+    let evicted = ages.len() - keep;
+    ages.select_nth_unstable_by_key(evicted - 1, |(updated, _)| *updated);
+    for (_, client) in &ages[..evicted] {
+        buckets.remove(client);
+    }
+}
+
+/// Caps how many searches and rebuilds run at once.
+pub struct ConcurrencyLimits {
+    searches: Arc<Semaphore>,
+    rebuilds: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            searches: Arc::new(Semaphore::new(config.max_concurrent_searches)),
+            rebuilds: Arc::new(Semaphore::new(config.max_concurrent_rebuilds)),
+        }
+    }
+}
+
+/// Answers 429 with `Retry-After` once the client's bucket is empty. Runs after
+/// authentication, so keyed clients are limited by key; requests refused for a
+/// bad key are limited by address in `auth`. Without a `RateLimiter` in app data
+/// nothing is limited.
+pub async fn rate_limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        let client = limiter.client(&req);
+        if let Err(wait) = limiter.acquire(&client) {
+            return Ok(req.into_response(rate_limited(wait)).map_into_right_body());
+        }
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// The 429 for a client that must wait `wait` before its next request.
+pub(crate) fn rate_limited(wait: Duration) -> HttpResponse {
+    metrics::REJECTIONS
+        .with_label_values(&["rate_limited"])
+        .inc();
+    HttpResponse::TooManyRequests()
+        .insert_header((
+            "Retry-After",
+            (wait.as_secs_f64().ceil() as u64).max(1).to_string(),
+        ))
+        .json(ErrorResponse {
+            error: "Rate limit exceeded; slow down".to_string(),
+        })
+}
+
+pub async fn limit_searches<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    limit_concurrency(|limits| &limits.searches, "search_overloaded", req, next).await
+}
+
+pub async fn limit_rebuilds<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    limit_concurrency(|limits| &limits.rebuilds, "rebuild_busy", req, next).await
+}
+
+/// Holds a permit from the chosen semaphore while the request runs, shedding it
+/// with a 503 when none is free rather than queueing it.
+async fn limit_concurrency<B: MessageBody>(
+    semaphore: fn(&ConcurrencyLimits) -> &Arc<Semaphore>,
+    reason: &'static str,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let limits = match req.app_data::<web::Data<ConcurrencyLimits>>() {
+        Some(limits) => limits.clone(),
+        None => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+    let _permit = match semaphore(&limits).clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            metrics::REJECTIONS.with_label_values(&[reason]).inc();
+            let response = HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", OVERLOADED_RETRY_AFTER))
+                .json(ErrorResponse {
+                    error: "The server is busy; retry shortly".to_string(),
+                });
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware, App};
+
+    #[test]
+    fn test_buckets_refill_at_the_configured_rate() {
+        let limiter = RateLimiter::new(
+            RateLimit {
+                per_sec: 2.0,
+                burst: 3,
+            },
+            None,
+        );
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire_at("key:upload-pipeline", start).is_ok());
+        }
+        let wait = limiter
+            .acquire_at("key:upload-pipeline", start)
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // Other clients have buckets of their own
+        assert!(limiter.acquire_at("ip:10.0.0.1", start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire_at("key:upload-pipeline", later).is_ok());
+        assert!(limiter.acquire_at("key:upload-pipeline", later).is_err());
+
+        // Idle time never adds more than a burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter
+                .acquire_at("key:upload-pipeline", much_later)
+                .is_ok());
+        }
+        assert!(limiter
+            .acquire_at("key:upload-pipeline", much_later)
+            .is_err());
+    }
+
+    #[test]
+    fn test_tracked_clients_are_capped() {
+        let limiter = RateLimiter::new(
+            RateLimit {
+                per_sec: 1.0,
+                burst: 1,
+            },
+            None,
+        );
+        let start = Instant::now();
+        // A client seen first but used since outlives the ones idle longer
+        assert!(limiter.acquire_at("key:upload-pipeline", start).is_ok());
+        for i in 0..MAX_TRACKED_CLIENTS + 500 {
+            let now = start + Duration::from_millis(i as u64);
+            assert!(limiter.acquire_at(&format!("ip:{}", i), now).is_ok());
+            if i == MAX_TRACKED_CLIENTS / 2 {
+                assert!(limiter.acquire_at("key:upload-pipeline", now).is_ok());
+            }
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED_CLIENTS);
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() > TRACKED_CLIENTS_AFTER_EVICTION);
+        assert!(buckets.contains_key("key:upload-pipeline"));
+        assert!(!buckets.contains_key("ip:0"));
+        assert!(buckets.contains_key(&format!("ip:{}", MAX_TRACKED_CLIENTS + 499)));
+    }
+
+    #[test]
+    fn test_limits_are_validated() {
+        let config = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            LimitsConfig::from_lookup(|key| vars.get(key).cloned())
+        };
+
+        assert_eq!(config(&[]).unwrap(), LimitsConfig::default());
+        let limits = config(&[("RATE_LIMIT_PER_SEC", "2.5")]).unwrap();
+        assert_eq!(
+            limits.rate,
+            Some(RateLimit {
+                per_sec: 2.5,
+                burst: 3
+            })
+        );
+
+        for vars in [
+            vec![("RATE_LIMIT_PER_SEC", "0")],
+            vec![("RATE_LIMIT_PER_SEC", "fast")],
+            vec![("RATE_LIMIT_PER_SEC", "10"), ("RATE_LIMIT_BURST", "0")],
+            vec![("SEARCH_MAX_CONCURRENT", "0")],
+            vec![("REBUILD_MAX_CONCURRENT", "-1")],
+        ] {
+            assert!(config(&vars).is_err(), "{:?} was accepted", vars);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_busy_rebuilds_are_shed() {
+        let limits = web::Data::new(ConcurrencyLimits::new(&LimitsConfig::default()));
+        let app = actix_web::test::init_service(
+            App::new().app_data(limits.clone()).service(
+                web::resource("/rebuild")
+                    .wrap(middleware::from_fn(limit_rebuilds))
+                    .route(web::post().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let rebuild = || {
+            actix_web::test::TestRequest::post()
+                .uri("/rebuild")
+                .to_request()
+        };
+
+        let running = limits.rebuilds.clone().try_acquire_owned().unwrap();
+        let resp = actix_web::test::call_service(&app, rebuild()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+
+        drop(running);
+        assert!(actix_web::test::call_service(&app, rebuild())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(limits.rebuilds.available_permits(), 1);
+    }
+}
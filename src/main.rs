@@ -1,158 +1,244 @@
 use actix_web::middleware::Logger;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use dotenv::dotenv;
+use actix_web::{web, App, HttpServer};
 use env_logger::Env;
-use serde::{Deserialize, Serialize};
-use serde_json;
+use std::env;
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 
-mod bigquery;
-mod index;
-mod videohash;
+use videohash_indexer::config::{Config, Settings};
+use videohash_indexer::index;
+use videohash_indexer::persistence;
+use videohash_indexer::policy::{self, SearchPolicy, SearchPolicyStore};
+use videohash_indexer::retry::RetryPolicy;
+use videohash_indexer::writeback::{self, WriteBack};
+use videohash_indexer::{ConcurrencyLimits, RateLimiter, RequireReady, VideoHashIndex};
 
-use index::create_shared_index;
-use index::VideoHashIndex;
-use videohash::VideoHash;
-
-#[derive(Serialize)]
-struct VideoMatch {
-    video_id: String,
-    similarity_percentage: f64,
-    is_duplicate: bool,
-}
-
-#[derive(Serialize)]
-struct SearchResponse {
-    match_found: bool,
-    match_details: Option<VideoMatch>,
-    hash_added: bool,
-}
-
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
-#[derive(Deserialize)]
-struct SearchRequest {
-    video_id: String,
-    hash: String,
-}
-
-async fn search(
-    req: web::Json<SearchRequest>,
-    index: web::Data<Arc<VideoHashIndex>>,
-) -> impl Responder {
-    const MAX_HAMMING_DISTANCE: u32 = 1;
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    let query_hash = match VideoHash::from_binary_string(&req.hash) {
-        Ok(hash) => hash,
+    // Flags, then the environment, then the config file. A misconfiguration would
+    // otherwise only surface on the first rebuild or write, so refuse to start
+    let settings = match Settings::load(env::args()) {
+        Ok(settings) => settings,
         Err(e) => {
-            return HttpResponse::BadRequest().json(ErrorResponse {
-                error: format!("Invalid hash format: {}", e),
-            });
+            println!("Error: {}", e);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
         }
     };
-
-    let similar_hashes = match index.find_within_distance(&query_hash, MAX_HAMMING_DISTANCE) {
-        Ok(results) => results,
+    let config = match Config::from_settings(&settings) {
+        Ok(config) => config,
         Err(e) => {
-            return HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Search failed: {}", e),
-            });
+            println!("Error: Invalid configuration: {}", e);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string()));
+        }
+    };
+    println!("Effective configuration: {:#?}", config);
+    let source = config.source.open();
+
+    // Inserts and deletions made through the API are streamed back to BigQuery when configured
+    let write_back = match config.write_back.clone() {
+        Some(write_back_config) => {
+            let auth = writeback::default_token_source(write_back_config.credentials.as_ref())
+                .await
+                .map_err(|e| {
+                    println!("Error: {}", e);
+                    io::Error::other(e.to_string())
+                })?;
+            println!(
+                "Writing new and deleted hashes back to {}",
+                write_back_config.table
+            );
+            Some(WriteBack::start(write_back_config, auth))
         }
+        None => None,
     };
 
-    if !similar_hashes.is_empty() {
-        let (video_id, distance) = similar_hashes[0].clone();
-        let similarity = 100.0 * (64.0 - distance as f64) / 64.0;
-
-        let response = SearchResponse {
-            match_found: true,
-            match_details: Some(VideoMatch {
-                video_id,
-                similarity_percentage: similarity,
-                is_duplicate: true,
-            }),
-            hash_added: false,
-        };
-
-        HttpResponse::Ok().json(response)
-    } else {
-        match index.add(req.video_id.clone(), &query_hash) {
-            Ok(_) => {
-                let response = SearchResponse {
-                    match_found: false,
-                    match_details: None,
-                    hash_added: true,
-                };
-
-                HttpResponse::Ok().json(response)
-            }
-            Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Failed to add hash: {}", e),
-            }),
+    // Duplicate decisions are recorded for analytics when DECISION_SINK is set
+    let decision_log = match config.decisions.clone() {
+        Some(decisions_config) => Some(decisions_config.start().await.map_err(|e| {
+            println!("Error: Could not start the decision log: {}", e);
+            io::Error::other(e.to_string())
+        })?),
+        None => None,
+    };
+
+    let shared_index = Arc::new(VideoHashIndex::with_config(config.index));
+
+    // Snapshots are optional; on fly.io SNAPSHOT_PATH points at the mounted volume
+    let snapshot_path = config.snapshot_path.clone();
+
+    // The incremental BigQuery sync resumes from the watermark its last run saved
+    let sync_state_path = config.sync_state_path.clone();
+    if let Some(path) = sync_state_path.as_ref().filter(|path| path.exists()) {
+        match persistence::read_json(path) {
+            Ok(watermark) => shared_index.advance_watermark(watermark),
+            Err(e) => println!("Warning: Could not load sync watermark {:?}: {}", path, e),
         }
     }
-}
 
-async fn delete_hash(
-    path: web::Path<String>,
-    index: web::Data<Arc<VideoHashIndex>>,
-) -> impl Responder {
-    let video_id = path.into_inner();
-
-    match index.remove(&video_id) {
-        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": format!("Hash with video_id {} successfully deleted", video_id)
-        })),
-        Ok(false) => HttpResponse::NotFound().json(ErrorResponse {
-            error: format!("Hash with video_id {} not found", video_id),
-        }),
-        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-            error: format!("Failed to remove hash: {}", e),
-        }),
+    // With WAL_PATH set, every add and remove is logged and replayed on top of the snapshot
+    let wal_path = config.wal_path.clone();
+    let mut rebuild_required = false;
+    let mut pending = Vec::new();
+
+    if let Some(wal_path) = &wal_path {
+        match shared_index.recover(snapshot_path.as_deref(), wal_path, config.wal_fsync) {
+            Ok(recovery) => {
+                println!(
+                    "Recovered {} video hashes from snapshot and mutation log",
+                    shared_index.len()
+                );
+                rebuild_required = recovery.rebuild_required;
+                pending = recovery.pending;
+            }
+            Err(e) => println!(
+                "Warning: Could not recover from mutation log {:?}: {}",
+                wal_path, e
+            ),
+        }
+    } else if let Some(path) = snapshot_path.as_ref().filter(|path| path.exists()) {
+        match shared_index.load_snapshot(path) {
+            Ok(count) => println!("Loaded {} video hashes from snapshot {:?}", count, path),
+            Err(e) => println!("Warning: Could not load index snapshot {:?}: {}", path, e),
+        }
     }
-}
 
-async fn rebuild_index(index: web::Data<Arc<VideoHashIndex>>) -> impl Responder {
-    match index.rebuild_from_bigquery().await {
-        Ok(count) => HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": format!("Index rebuilt successfully with {} video hashes", count)
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(ErrorResponse {
-            error: format!("Failed to rebuild index: {}", e),
-        }),
+    // Load in the background so /readyz can report progress; until the load
+    // succeeds the index is not ready and, by default, /search answers 503
+    if rebuild_required || shared_index.needs_rebuild() {
+        println!(
+            "Loading index from {}; not ready until the load completes",
+            source.name()
+        );
+        index::spawn_initial_load(
+            shared_index.clone(),
+            source.clone(),
+            pending,
+            RetryPolicy {
+                max_attempts: u32::MAX,
+                initial_backoff: Duration::from_secs(5),
+                max_backoff: Duration::from_secs(300),
+            },
+        );
+    } else {
+        shared_index.mark_ready();
     }
-}
+    let require_ready = config.search_requires_ready;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    dotenv::dotenv().ok();
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-
-    let shared_index = create_shared_index();
+    index::spawn_merge_worker(shared_index.clone(), Duration::from_secs(5));
 
-    if shared_index.needs_rebuild() {
-        match shared_index.rebuild_from_bigquery().await {
-            Ok(count) => println!("Successfully initialized index with {} video hashes", count),
-            Err(e) => println!("Warning: Could not initialize index from BigQuery: {}", e),
-        }
+    if let Some(path) = &snapshot_path {
+        index::spawn_snapshot_worker(shared_index.clone(), path.clone(), config.snapshot_interval);
     }
 
-    println!("Starting videohash indexer service on http://0.0.0.0:8080");
+    // Pull hashes written by other pipelines without a full rebuild
+    if let Some(interval) = config.sync_interval {
+        index::spawn_sync_worker(
+            shared_index.clone(),
+            source.clone(),
+            interval,
+            sync_state_path.clone(),
+        );
+    }
 
+    println!(
+        "Starting videohash indexer service on http://{}",
+        config.bind
+    );
+
+    // The search policy can change while serving: PATCH /admin/search-policy, or
+    // SIGHUP to re-read it from the config file. The environment is fixed once the
+    // process has started, so variables set there still win over the file
+    let search_policy = Arc::new(SearchPolicyStore::new(config.search));
+    policy::reload_on_hangup(search_policy.clone(), || {
+        let settings = Settings::reload(env::args())?;
+        SearchPolicy::from_lookup(|key| settings.get(key))
+    })?;
+    if config.api_keys.is_empty() {
+        println!(
+            "Warning: No API_KEYS configured; searches are open and admin endpoints are disabled"
+        );
+    }
+    let api_keys = web::Data::new(config.api_keys.clone());
+    // Shared by every worker, so the limits hold for the process as a whole
+    let concurrency_limits = web::Data::new(ConcurrencyLimits::new(&config.limits));
+    let rate_limiter = config.limits.rate.map(|rate| {
+        web::Data::new(RateLimiter::new(
+            rate,
+            config.limits.client_ip_header.clone(),
+        ))
+    });
+
+    let server_index = shared_index.clone();
+    let search_policy = web::Data::from(search_policy);
+    let server_write_back = write_back
+        .as_ref()
+        .map(|(handle, _)| web::Data::new(handle.clone()));
+    let server_decision_log = decision_log
+        .as_ref()
+        .map(|(handle, _)| web::Data::new(handle.clone()));
     HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
             .wrap(Logger::default())
-            .app_data(web::Data::new(shared_index.clone()))
-            .route("/search", web::post().to(search))
-            .route("/hash/{video_id}", web::delete().to(delete_hash))
-            .route("/rebuild", web::post().to(rebuild_index))
+            .configure(videohash_indexer::configure(
+                server_index.clone(),
+                source.clone(),
+            ))
+            .app_data(search_policy.clone())
+            .app_data(api_keys.clone())
+            .app_data(concurrency_limits.clone());
+        if let Some(rate_limiter) = &rate_limiter {
+            app = app.app_data(rate_limiter.clone());
+        }
+        if let Some(write_back) = &server_write_back {
+            app = app.app_data(write_back.clone());
+        }
+        if let Some(decision_log) = &server_decision_log {
+            app = app.app_data(decision_log.clone());
+        }
+        if require_ready {
+            app = app.app_data(web::Data::new(RequireReady));
+        }
+        app
     })
-    .bind("0.0.0.0:8080")?
+    .bind(config.bind)?
     .run()
-    .await
+    .await?;
+
+    if let Some(path) = &snapshot_path {
+        match shared_index.save_snapshot(path) {
+            Ok(_) => println!("Saved index snapshot to {:?} on shutdown", path),
+            Err(e) => println!("Warning: Could not save index snapshot on shutdown: {}", e),
+        }
+    }
+
+    // Background writers stop once their last handle is gone and their queue is drained
+    let mut writers = Vec::new();
+    if let Some((handle, worker)) = write_back {
+        drop(handle);
+        writers.push(("BigQuery write-back rows", worker));
+    }
+    if let Some((handle, worker)) = decision_log {
+        println!(
+            "Decision log: {} written, {} dropped, {} failed",
+            handle.written(),
+            handle.dropped(),
+            handle.failed()
+        );
+        drop(handle);
+        writers.push(("duplicate decisions", worker));
+    }
+    for (what, worker) in writers {
+        if tokio::time::timeout(Duration::from_secs(10), worker)
+            .await
+            .is_err()
+        {
+            println!("Warning: Timed out flushing {} on shutdown", what);
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::index::VideoHashIndex;
+
+lazy_static! {
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "videohash_http_request_duration_seconds",
+        "Time spent answering HTTP requests, by route pattern",
+        &["route", "method", "status"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0]
+    )
+    .unwrap();
+    pub(crate) static ref SEARCH_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "videohash_search_outcomes_total",
+        "Searches by outcome: exact_match, match, inserted or no_match",
+        &["outcome"]
+    )
+    .unwrap();
+    pub(crate) static ref DELETES: IntCounterVec = register_int_counter_vec!(
+        "videohash_deletes_total",
+        "Delete requests by result: deleted or not_found",
+        &["result"]
+    )
+    .unwrap();
+    pub(crate) static ref MATCH_DISTANCE: Histogram = register_histogram!(
+        "videohash_match_hamming_distance",
+        "Hamming distances of the hashes returned by threshold searches",
+        (0..=16).map(f64::from).collect()
+    )
+    .unwrap();
+    pub(crate) static ref MIH_BUILDS: HistogramVec = register_histogram_vec!(
+        "videohash_mih_build_duration_seconds",
+        "Time spent building an MIH segment, by cause: merge or rebuild",
+        &["cause"],
+        vec![0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+    )
+    .unwrap();
+    pub(crate) static ref SOURCE_LOADS: IntCounterVec = register_int_counter_vec!(
+        "videohash_source_loads_total",
+        "Rebuilds and incremental syncs from the hash source, by outcome",
+        &["kind", "outcome"]
+    )
+    .unwrap();
+    pub(crate) static ref SOURCE_LOAD_DURATION: HistogramVec = register_histogram_vec!(
+        "videohash_source_load_duration_seconds",
+        "Time spent on successful rebuilds and syncs from the hash source",
+        &["kind"],
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0]
+    )
+    .unwrap();
+    pub(crate) static ref SOURCE_ROWS: IntCounterVec = register_int_counter_vec!(
+        "videohash_source_rows_loaded_total",
+        "Hashes read from the hash source",
+        &["kind"]
+    )
+    .unwrap();
+    pub(crate) static ref BIGQUERY_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "videohash_bigquery_requests_total",
+        "BigQuery API requests by call and outcome: ok, retried or failed",
+        &["call", "outcome"]
+    )
+    .unwrap();
+    pub(crate) static ref REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "videohash_requests_rejected_total",
+        "Requests turned away by a limit: rate_limited, search_overloaded or rebuild_busy",
+        &["reason"]
+    )
+    .unwrap();
+    static ref INDEX_SIZE: IntGauge =
+        register_int_gauge!("videohash_index_size", "Hashes currently indexed").unwrap();
+    static ref INDEX_PENDING: IntGauge = register_int_gauge!(
+        "videohash_index_pending_changes",
+        "Inserts and deletions in the delta, waiting for the next merge"
+    )
+    .unwrap();
+    static ref INDEX_READY: IntGauge = register_int_gauge!(
+        "videohash_index_ready",
+        "1 once the index has been loaded, 0 while it is loading"
+    )
+    .unwrap();
+}
+
+/// Records the latency of every request routed through the shared router, labelled
+/// with the route pattern rather than the path so ids do not create new series.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let timer = std::time::Instant::now();
+    let method = req.method().to_string();
+    let response = next.call(req).await?;
+
+    let route = response
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .observe(timer.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+/// Prometheus text exposition of every metric, with the index gauges sampled now.
+pub async fn metrics(index: web::Data<Arc<VideoHashIndex>>) -> HttpResponse {
+    let status = index.status();
+    INDEX_SIZE.set(status.indexed as i64);
+    INDEX_PENDING.set(status.pending_changes as i64);
+    INDEX_READY.set(status.ready as i64);
+
+    let mut body = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut body) {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to encode metrics: {}", e));
+    }
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body)
+}
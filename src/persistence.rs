@@ -0,0 +1,257 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Identifies a videohash index snapshot file.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"VHIX";
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// magic + version + last log sequence + entry count.
+const HEADER_LEN: usize = 4 + 4 + 8 + 8;
+const CHECKSUM_LEN: usize = 4;
+
+/// Contents of a snapshot file.
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotContents {
+    /// Last mutation log sequence number reflected in `entries`, 0 if none.
+    pub last_sequence: u64,
+    pub entries: Vec<(String, u64)>,
+}
+
+/// Writes `entries` to `path` in the snapshot format.
+///
+/// Layout (little endian): magic `VHIX`, `u32` version, `u64` last mutation log
+/// sequence covered, `u64` entry count, then per entry a `u32` video_id length, the
+/// UTF-8 video_id and the `u64` hash, followed by a CRC32 of everything before it.
+/// The file is written next to `path` and renamed into place, so a crash mid-write
+/// never replaces a good snapshot with a partial one.
+pub fn write_snapshot(
+    path: &Path,
+    last_sequence: u64,
+    entries: &[(String, u64)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tmp_path = temp_path(path);
+    let file = File::create(&tmp_path)
+        .map_err(|e| format!("Failed to create snapshot file {:?}: {}", tmp_path, e))?;
+    let mut writer = ChecksumWriter::new(BufWriter::new(file));
+
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    writer.write_all(&last_sequence.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (video_id, hash) in entries {
+        let id_len = u32::try_from(video_id.len())
+            .map_err(|_| format!("video_id too long to snapshot: {} bytes", video_id.len()))?;
+        writer.write_all(&id_len.to_le_bytes())?;
+        writer.write_all(video_id.as_bytes())?;
+        writer.write_all(&hash.to_le_bytes())?;
+    }
+
+    let ChecksumWriter { mut inner, hasher } = writer;
+    inner.write_all(&hasher.finalize().to_le_bytes())?;
+    let file = inner
+        .into_inner()
+        .map_err(|e| format!("Failed to flush snapshot file {:?}: {}", tmp_path, e))?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Failed to move snapshot into place at {:?}: {}", path, e))?;
+    sync_parent_dir(path);
+
+    Ok(())
+}
+
+/// Reads and validates a snapshot written by `write_snapshot`.
+pub fn read_snapshot(path: &Path) -> Result<SnapshotContents, Box<dyn Error + Send + Sync>> {
+    let data =
+        fs::read(path).map_err(|e| format!("Failed to read snapshot file {:?}: {}", path, e))?;
+
+    if data.len() < 8 || &data[0..4] != SNAPSHOT_MAGIC {
+        return Err(format!("{:?} is not a videohash index snapshot", path).into());
+    }
+
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "Unsupported snapshot version {} in {:?} (expected {})",
+            version, path, SNAPSHOT_VERSION
+        )
+        .into());
+    }
+    if data.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(format!("Snapshot file {:?} is truncated", path).into());
+    }
+
+    let (body, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+    if crc32fast::hash(body) != expected {
+        return Err(format!("Snapshot file {:?} failed checksum validation", path).into());
+    }
+
+    let last_sequence = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    let count = u64::from_le_bytes(body[16..HEADER_LEN].try_into().unwrap()) as usize;
+    let mut cursor = Cursor {
+        data: body,
+        pos: HEADER_LEN,
+    };
+    // Each entry takes at least 12 bytes, so a bogus count cannot force a huge allocation
+    let mut entries = Vec::with_capacity(count.min(body.len() / 12));
+    for _ in 0..count {
+        let id_len = u32::from_le_bytes(cursor.take(4)?.try_into().unwrap()) as usize;
+        let video_id = String::from_utf8(cursor.take(id_len)?.to_vec())
+            .map_err(|e| format!("Invalid video_id in snapshot: {}", e))?;
+        let hash = u64::from_le_bytes(cursor.take(8)?.try_into().unwrap());
+        entries.push((video_id, hash));
+    }
+
+    if cursor.pos != body.len() {
+        return Err(format!("Snapshot file {:?} has trailing data", path).into());
+    }
+
+    Ok(SnapshotContents {
+        last_sequence,
+        entries,
+    })
+}
+
+/// Writes `value` to `path` as JSON, replacing any previous file atomically.
+pub fn write_json<T: Serialize>(
+    path: &Path,
+    value: &T,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tmp_path = temp_path(path);
+    let json = serde_json::to_vec(value)?;
+    let mut file = File::create(&tmp_path)
+        .map_err(|e| format!("Failed to create state file {:?}: {}", tmp_path, e))?;
+    file.write_all(&json)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Failed to move state file into place at {:?}: {}", path, e))?;
+    sync_parent_dir(path);
+    Ok(())
+}
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error + Send + Sync>> {
+    let data =
+        fs::read(path).map_err(|e| format!("Failed to read state file {:?}: {}", path, e))?;
+    serde_json::from_slice(&data)
+        .map_err(|e| format!("Invalid state file {:?}: {}", path, e).into())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+/// Makes the rename durable. Best effort: not every platform can fsync a directory.
+fn sync_parent_dir(path: &Path) {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+/// Passes writes through while feeding them to a CRC32 hasher.
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or("Snapshot entry runs past the end of the file")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_snapshot(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "videohash-{}-{}.snapshot",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_snapshot("round-trip");
+        let entries = vec![
+            ("video-001".to_string(), 0),
+            ("video-002".to_string(), u64::MAX),
+            ("vidéo-003".to_string(), 0xAAAAAAAAAAAAAAAA),
+        ];
+
+        write_snapshot(&path, 7, &entries)?;
+        assert_eq!(
+            read_snapshot(&path)?,
+            SnapshotContents {
+                last_sequence: 7,
+                entries,
+            }
+        );
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corruption() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_snapshot("corrupt");
+        write_snapshot(&path, 0, &[("video-001".to_string(), 42)])?;
+
+        let mut data = fs::read(&path)?;
+        data[HEADER_LEN + 5] ^= 0xFF;
+        fs::write(&path, &data)?;
+        assert!(read_snapshot(&path).is_err());
+
+        data.truncate(HEADER_LEN);
+        fs::write(&path, &data)?;
+        assert!(read_snapshot(&path).is_err());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
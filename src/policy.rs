@@ -0,0 +1,278 @@
+use std::error::Error;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::parse_flag;
+use crate::writeback::parse_var;
+
+/// Hashes are 64 bits, so no distance can exceed this.
+const HASH_BITS: u32 = 64;
+
+/// What `/search` and `/neighbors` count as a duplicate, and whether a search
+/// that finds none inserts the hash. Can be changed while the service runs.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchPolicy {
+    /// Threshold used when a request does not set `max_distance`.
+    pub default_max_distance: u32,
+    /// Largest threshold a request may ask for. Beyond this a search matches a large
+    /// part of the index and stops meaning "duplicate".
+    pub max_allowed_distance: u32,
+    /// Matches less similar than this percentage never count as duplicates,
+    /// whatever distance was asked for. 0 leaves the distances alone.
+    pub min_similarity: f64,
+    /// Insert hashes that match nothing. `false` makes every search read-only.
+    pub insert_on_miss: bool,
+}
+
+impl Default for SearchPolicy {
+    fn default() -> Self {
+        Self {
+            default_max_distance: 1,
+            max_allowed_distance: 16,
+            min_similarity: 0.0,
+            insert_on_miss: true,
+        }
+    }
+}
+
+impl SearchPolicy {
+    /// Reads the `SEARCH_*` settings, falling back to the defaults.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let defaults = Self::default();
+        let policy = Self {
+            default_max_distance: parse_var(
+                &lookup,
+                "SEARCH_DEFAULT_MAX_DISTANCE",
+                defaults.default_max_distance,
+            )?,
+            max_allowed_distance: parse_var(
+                &lookup,
+                "SEARCH_MAX_ALLOWED_DISTANCE",
+                defaults.max_allowed_distance,
+            )?,
+            min_similarity: parse_var(&lookup, "SEARCH_MIN_SIMILARITY", defaults.min_similarity)?,
+            insert_on_miss: match lookup("SEARCH_INSERT_ON_MISS") {
+                Some(value) => parse_flag("SEARCH_INSERT_ON_MISS", &value)?,
+                None => defaults.insert_on_miss,
+            },
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.max_allowed_distance > HASH_BITS
+            || self.default_max_distance > self.max_allowed_distance
+        {
+            return Err(format!(
+                "The default max distance ({}) must not exceed the max allowed distance ({}), \
+                 which must be at most {}",
+                self.default_max_distance, self.max_allowed_distance, HASH_BITS
+            )
+            .into());
+        }
+        if !(0.0..=100.0).contains(&self.min_similarity) {
+            return Err(format!(
+                "The minimum similarity must be a percentage between 0 and 100, got {}",
+                self.min_similarity
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The threshold to use for a request that asked for `requested`.
+    pub fn max_distance(&self, requested: Option<u32>) -> u32 {
+        requested
+            .unwrap_or(self.default_max_distance)
+            .min(self.max_allowed_distance)
+            .min(self.similarity_distance())
+    }
+
+    /// Whether a search that asked to insert on a miss may do so.
+    pub fn should_insert(&self, requested: bool) -> bool {
+        requested && self.insert_on_miss
+    }
+
+    /// The largest distance that is still `min_similarity` percent similar.
+    fn similarity_distance(&self) -> u32 {
+        (f64::from(HASH_BITS) * (100.0 - self.min_similarity) / 100.0).floor() as u32
+    }
+}
+
+/// A change to some fields of the policy; the rest keep their current values.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SearchPolicyUpdate {
+    pub default_max_distance: Option<u32>,
+    pub max_allowed_distance: Option<u32>,
+    pub min_similarity: Option<f64>,
+    pub insert_on_miss: Option<bool>,
+}
+
+impl SearchPolicyUpdate {
+    fn apply(&self, policy: SearchPolicy) -> SearchPolicy {
+        SearchPolicy {
+            default_max_distance: self
+                .default_max_distance
+                .unwrap_or(policy.default_max_distance),
+            max_allowed_distance: self
+                .max_allowed_distance
+                .unwrap_or(policy.max_allowed_distance),
+            min_similarity: self.min_similarity.unwrap_or(policy.min_similarity),
+            insert_on_miss: self.insert_on_miss.unwrap_or(policy.insert_on_miss),
+        }
+    }
+}
+
+/// The policy in force. Requests copy it once and use that copy throughout, so a
+/// change never applies to half a request.
+pub struct SearchPolicyStore {
+    current: RwLock<SearchPolicy>,
+}
+
+impl SearchPolicyStore {
+    pub fn new(policy: SearchPolicy) -> Self {
+        Self {
+            current: RwLock::new(policy),
+        }
+    }
+
+    pub fn current(&self) -> SearchPolicy {
+        // The policy is replaced whole, so a poisoned lock still holds a valid one
+        *self.current.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Validates `update` against the current policy and swaps in the result,
+    /// logging the old and new values. `origin` says who asked, for the log.
+    pub fn update(
+        &self,
+        update: &SearchPolicyUpdate,
+        origin: &str,
+    ) -> Result<SearchPolicy, Box<dyn Error + Send + Sync>> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let next = update.apply(*current);
+        next.validate()?;
+
+        if next == *current {
+            log::info!("Search policy unchanged by {}: {:?}", origin, next);
+        } else {
+            log::info!(
+                "Search policy changed by {}: {:?} -> {:?}",
+                origin,
+                *current,
+                next
+            );
+            *current = next;
+        }
+        Ok(next)
+    }
+
+    /// Replaces the whole policy, as a reloaded config file does.
+    pub fn replace(
+        &self,
+        policy: SearchPolicy,
+        origin: &str,
+    ) -> Result<SearchPolicy, Box<dyn Error + Send + Sync>> {
+        self.update(
+            &SearchPolicyUpdate {
+                default_max_distance: Some(policy.default_max_distance),
+                max_allowed_distance: Some(policy.max_allowed_distance),
+                min_similarity: Some(policy.min_similarity),
+                insert_on_miss: Some(policy.insert_on_miss),
+            },
+            origin,
+        )
+    }
+}
+
+/// Re-reads the policy with `reload` on every SIGHUP. A policy that fails to load
+/// or validate is logged and the current one stays in force.
+#[cfg(unix)]
+pub fn reload_on_hangup<F>(store: Arc<SearchPolicyStore>, reload: F) -> std::io::Result<()>
+where
+    F: Fn() -> Result<SearchPolicy, Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reload().and_then(|policy| store.replace(policy, "SIGHUP")) {
+                log::error!("Keeping the current search policy: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// There is no SIGHUP here; the policy can still be changed over the admin API.
+#[cfg(not(unix))]
+pub fn reload_on_hangup<F>(_store: Arc<SearchPolicyStore>, _reload: F) -> std::io::Result<()>
+where
+    F: Fn() -> Result<SearchPolicy, Box<dyn Error + Send + Sync>> + Send + 'static,
+{
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity_caps_the_distance() {
+        let policy = SearchPolicy {
+            default_max_distance: 4,
+            max_allowed_distance: 16,
+            min_similarity: 95.0,
+            insert_on_miss: true,
+        };
+        // 95% of 64 bits leaves 3 that may differ
+        assert_eq!(policy.max_distance(None), 3);
+        assert_eq!(policy.max_distance(Some(2)), 2);
+        assert_eq!(policy.max_distance(Some(40)), 3);
+
+        let policy = SearchPolicy::default();
+        assert_eq!(policy.max_distance(None), 1);
+        assert_eq!(policy.max_distance(Some(40)), 16);
+    }
+
+    #[test]
+    fn test_updates_are_validated_before_they_apply() {
+        let store = SearchPolicyStore::new(SearchPolicy::default());
+
+        let updated = store
+            .update(
+                &SearchPolicyUpdate {
+                    default_max_distance: Some(3),
+                    insert_on_miss: Some(false),
+                    ..Default::default()
+                },
+                "test",
+            )
+            .unwrap();
+        assert_eq!(updated.default_max_distance, 3);
+        assert_eq!(updated.max_allowed_distance, 16);
+        assert!(!store.current().should_insert(true));
+
+        for update in [
+            SearchPolicyUpdate {
+                default_max_distance: Some(17),
+                ..Default::default()
+            },
+            SearchPolicyUpdate {
+                max_allowed_distance: Some(2),
+                ..Default::default()
+            },
+            SearchPolicyUpdate {
+                min_similarity: Some(101.0),
+                ..Default::default()
+            },
+        ] {
+            assert!(store.update(&update, "test").is_err());
+        }
+        assert_eq!(store.current(), updated);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Whether a failed request can be sent again.
+pub(crate) enum SendError {
+    Retryable(Box<dyn Error + Send + Sync>),
+    Fatal(Box<dyn Error + Send + Sync>),
+}
+
+/// How often, and how patiently, a failed call is tried again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled on every further one up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (starting at 1).
+    ///
+    /// Somewhere between half and all of the exponential delay, so instances that
+    /// failed together do not all retry at the same moment.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        jitter(exponential)
+    }
+}
+
+/// A random duration between `delay / 2` and `delay`.
+pub fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let spread = half.as_nanos() as u64;
+    if spread == 0 {
+        return delay;
+    }
+    half + Duration::from_nanos(random_u64() % (spread + 1))
+}
+
+/// Every `RandomState` is seeded differently, which is all the randomness backoff needs.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            let late = policy.backoff(40);
+            assert!(late >= Duration::from_millis(500) && late <= Duration::from_secs(1));
+        }
+        assert_eq!(jitter(Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -0,0 +1,390 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::bigquery::{self, BigQuerySource, LoadSummary, Watermark};
+use crate::bigquery_api::BigQueryClient;
+use crate::videohash::VideoHash;
+
+/// Rows handed to the page callback at a time by the file sources.
+const FILE_PAGE_SIZE: usize = 10_000;
+
+/// Receives each page of `(video_id, hash)` pairs as a source produces it.
+pub type PageHandler<'a> =
+    dyn FnMut(Vec<(String, VideoHash)>) -> Result<(), Box<dyn Error + Send + Sync>> + Send + 'a;
+
+/// Somewhere the index can be (re)loaded from.
+#[async_trait]
+pub trait HashSource: Send + Sync {
+    /// Human readable name for logs, such as the table or file path.
+    fn name(&self) -> String;
+
+    /// Streams every hash, or only those newer than `since` for sources that track
+    /// a watermark. Sources without one ignore `since` and return everything.
+    async fn load(
+        &self,
+        since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>>;
+}
+
+#[async_trait]
+impl HashSource for BigQuerySource {
+    fn name(&self) -> String {
+        format!("BigQuery table {}", self.table)
+    }
+
+    async fn load(
+        &self,
+        since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        let client = self.connect().await?;
+        bigquery::stream_video_hashes(&client, self, since, on_page).await
+    }
+}
+
+/// A `BigQuerySource` read through a given client rather than one built from the
+/// environment's credentials on every load.
+pub struct BigQueryReader {
+    pub source: BigQuerySource,
+    pub client: Arc<dyn BigQueryClient>,
+}
+
+#[async_trait]
+impl HashSource for BigQueryReader {
+    fn name(&self) -> String {
+        self.source.name()
+    }
+
+    async fn load(
+        &self,
+        since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        bigquery::stream_video_hashes(self.client.as_ref(), &self.source, since, on_page).await
+    }
+}
+
+/// One hash per line as a JSON object shaped like a `/search` request body:
+/// `{"video_id": "video-001", "hash": "0101..."}`. Blank lines are ignored.
+#[derive(Clone, Debug)]
+pub struct JsonlSource {
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct JsonlRow {
+    video_id: String,
+    #[serde(alias = "videohash")]
+    hash: String,
+}
+
+#[async_trait]
+impl HashSource for JsonlSource {
+    fn name(&self) -> String {
+        format!("JSONL file {:?}", self.path)
+    }
+
+    async fn load(
+        &self,
+        _since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        let mut page = PageBuffer::new(on_page);
+        let mut lines = open_lines(&self.path).await?;
+        let mut line_number = 0;
+
+        while let Some(line) = lines.next_line().await? {
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JsonlRow>(&line) {
+                Ok(row) => page.push(&self.path, line_number, row.video_id, &row.hash)?,
+                Err(e) => log::warn!("Skipping {:?} line {}: {}", self.path, line_number, e),
+            }
+        }
+
+        page.finish()
+    }
+}
+
+/// Comma separated with a header row naming at least a `video_id` column and a
+/// `hash` (or `videohash`) column; any other columns are ignored. Fields may be
+/// double quoted.
+#[derive(Clone, Debug)]
+pub struct CsvSource {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl HashSource for CsvSource {
+    fn name(&self) -> String {
+        format!("CSV file {:?}", self.path)
+    }
+
+    async fn load(
+        &self,
+        _since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        let mut page = PageBuffer::new(on_page);
+        let mut lines = open_lines(&self.path).await?;
+
+        let header = lines
+            .next_line()
+            .await?
+            .ok_or_else(|| format!("CSV file {:?} is empty", self.path))?;
+        let columns = split_csv_line(&header);
+        let position = |names: &[&str]| {
+            columns
+                .iter()
+                .position(|column| names.contains(&column.trim().to_lowercase().as_str()))
+        };
+        let (id_column, hash_column) =
+            match (position(&["video_id"]), position(&["hash", "videohash"])) {
+                (Some(id), Some(hash)) => (id, hash),
+                _ => {
+                    return Err(format!(
+                        "CSV file {:?} needs a header with video_id and hash columns, got: {}",
+                        self.path, header
+                    )
+                    .into())
+                }
+            };
+
+        let mut line_number = 1;
+        while let Some(line) = lines.next_line().await? {
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = split_csv_line(&line);
+            match (fields.get(id_column), fields.get(hash_column)) {
+                (Some(video_id), Some(hash)) => {
+                    page.push(&self.path, line_number, video_id.clone(), hash)?
+                }
+                _ => log::warn!(
+                    "Skipping {:?} line {}: missing video_id or hash",
+                    self.path,
+                    line_number
+                ),
+            }
+        }
+
+        page.finish()
+    }
+}
+
+/// Which hash source the index is loaded from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceConfig {
+    BigQuery(Box<BigQuerySource>),
+    Jsonl(PathBuf),
+    Csv(PathBuf),
+}
+
+impl SourceConfig {
+    /// Picks the source named by `HASH_SOURCE`: `bigquery` (the default), `jsonl` or
+    /// `csv`. The file sources read `HASH_SOURCE_PATH`, which must exist.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let kind = lookup("HASH_SOURCE").unwrap_or_else(|| "bigquery".to_string());
+        let path = || -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+            let path = lookup("HASH_SOURCE_PATH")
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("HASH_SOURCE={} requires HASH_SOURCE_PATH", kind))?;
+            if !path.is_file() {
+                return Err(format!("HASH_SOURCE_PATH {:?} is not a readable file", path).into());
+            }
+            Ok(path)
+        };
+
+        match kind.to_lowercase().as_str() {
+            "bigquery" => Ok(Self::BigQuery(Box::new(BigQuerySource::from_lookup(
+                &lookup,
+            )?))),
+            "jsonl" => Ok(Self::Jsonl(path()?)),
+            "csv" => Ok(Self::Csv(path()?)),
+            other => Err(format!(
+                "Unknown HASH_SOURCE '{}', expected 'bigquery', 'jsonl' or 'csv'",
+                other
+            )
+            .into()),
+        }
+    }
+
+    pub fn open(&self) -> Arc<dyn HashSource> {
+        match self {
+            Self::BigQuery(source) => Arc::new((**source).clone()),
+            Self::Jsonl(path) => Arc::new(JsonlSource { path: path.clone() }),
+            Self::Csv(path) => Arc::new(CsvSource { path: path.clone() }),
+        }
+    }
+}
+
+async fn open_lines(
+    path: &Path,
+) -> Result<tokio::io::Lines<BufReader<tokio::fs::File>>, Box<dyn Error + Send + Sync>> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to open hash source {:?}: {}", path, e))?;
+    Ok(BufReader::new(file).lines())
+}
+
+/// Collects parsed rows and flushes them to the page handler in fixed-size pages.
+struct PageBuffer<'h, 'a> {
+    on_page: &'h mut PageHandler<'a>,
+    rows: Vec<(String, VideoHash)>,
+    summary: LoadSummary,
+}
+
+impl<'h, 'a> PageBuffer<'h, 'a> {
+    fn new(on_page: &'h mut PageHandler<'a>) -> Self {
+        Self {
+            on_page,
+            rows: Vec::new(),
+            summary: LoadSummary::default(),
+        }
+    }
+
+    fn push(
+        &mut self,
+        path: &Path,
+        line_number: usize,
+        video_id: String,
+        hash: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match VideoHash::from_binary_string(hash.trim()) {
+            Ok(hash) => self.rows.push((video_id, hash)),
+            Err(e) => {
+                log::warn!("Skipping {:?} line {}: {}", path, line_number, e);
+                return Ok(());
+            }
+        }
+        if self.rows.len() >= FILE_PAGE_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let rows = std::mem::take(&mut self.rows);
+        self.summary.loaded += rows.len();
+        (self.on_page)(rows)
+    }
+
+    fn finish(mut self) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        if !self.rows.is_empty() {
+            self.flush()?;
+        }
+        Ok(self.summary)
+    }
+}
+
+/// Splits one CSV record. Quoted fields may contain commas and `""` escapes; records
+/// spanning several lines are not supported.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("videohash-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn load_all(
+        source: &dyn HashSource,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+        let mut loaded = Vec::new();
+        let summary = source
+            .load(None, &mut |page| {
+                loaded.extend(page.into_iter().map(|(id, hash)| (id, hash.hash)));
+                Ok(())
+            })
+            .await?;
+        assert_eq!(summary.loaded, loaded.len());
+        assert!(summary.watermark.is_none());
+        Ok(loaded)
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_source() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zeros = "0".repeat(64);
+        let ones = "1".repeat(64);
+        let path = temp_file(
+            "seed.jsonl",
+            &format!(
+                "{{\"video_id\": \"video-001\", \"hash\": \"{}\"}}\n\n\
+                 not json\n\
+                 {{\"video_id\": \"video-002\", \"hash\": \"0101\"}}\n\
+                 {{\"video_id\": \"video-003\", \"videohash\": \"{}\", \"insert\": false}}\n",
+                zeros, ones
+            ),
+        );
+
+        let loaded = load_all(&JsonlSource { path: path.clone() }).await?;
+        assert_eq!(
+            loaded,
+            vec![
+                ("video-001".to_string(), zeros),
+                ("video-003".to_string(), ones),
+            ]
+        );
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_csv_source() -> Result<(), Box<dyn Error + Send + Sync>> {
+        let zeros = "0".repeat(64);
+        let path = temp_file(
+            "seed.csv",
+            &format!(
+                "created_at,hash,video_id\r\n\
+                 2024-01-01,{0},\"video, \"\"quoted\"\"\"\r\n\
+                 2024-01-02,{0}\r\n\
+                 2024-01-03,nope,video-003\r\n",
+                zeros
+            ),
+        );
+
+        let loaded = load_all(&CsvSource { path: path.clone() }).await?;
+        assert_eq!(loaded, vec![("video, \"quoted\"".to_string(), zeros)]);
+
+        std::fs::write(&path, "id,hash\nvideo-001,0\n")?;
+        assert!(load_all(&CsvSource { path: path.clone() }).await.is_err());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+//! Synchronization primitives used by the index.
+//!
+//! Builds with `--cfg videohash_loom` swap in loom's model-checked versions so
+//! `tests/loom_tests.rs` can explore every interleaving of concurrent writers and readers.
+
+#[cfg(videohash_loom)]
+pub(crate) use loom::sync::{Mutex, MutexGuard, RwLock};
+
+#[cfg(not(videohash_loom))]
+pub(crate) use std::sync::{Mutex, MutexGuard, RwLock};
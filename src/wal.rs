@@ -0,0 +1,539 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Frame header: `u32` payload length + `u32` CRC32 of the payload.
+const FRAME_HEADER_LEN: usize = 8;
+
+const RECORD_INSERT: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+const RECORD_REBUILD: u8 = 3;
+
+/// A single logged index mutation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalRecord {
+    Insert {
+        video_id: String,
+        hash: u64,
+    },
+    Remove {
+        video_id: String,
+    },
+    /// The index was replaced wholesale from an external source such as BigQuery.
+    /// The new contents are not in the log, only the fact that it happened.
+    Rebuild,
+}
+
+/// When appended records are flushed to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every record. Nothing acknowledged is lost on power failure.
+    Always,
+    /// Leave flushing to the OS. Survives process crashes but not host crashes.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => Err(format!(
+                "Invalid fsync policy '{}', expected 'always' or 'never'",
+                other
+            )
+            .into()),
+        }
+    }
+}
+
+/// Everything read back from a log and its rotated segments.
+#[derive(Debug, Default)]
+pub struct LogContents {
+    /// Records in sequence order.
+    pub records: Vec<(u64, WalRecord)>,
+    /// Highest sequence number seen, or 0 for an empty log.
+    pub last_sequence: u64,
+}
+
+/// Append-only log of index mutations.
+///
+/// Each record is framed as `[u32 len][u32 crc32][payload]` where the payload is a
+/// `u64` sequence number, a record type byte and the record fields. `rotate` moves
+/// the active file aside as `<path>.<last sequence>` so it can be deleted once a
+/// snapshot covers it.
+pub struct MutationLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Byte length of the complete records in the active file.
+    len: u64,
+    next_sequence: u64,
+    fsync: FsyncPolicy,
+    /// Set when a failed append could not be cleaned up; the log refuses further
+    /// appends rather than write them after a partial record.
+    failed: bool,
+}
+
+impl MutationLog {
+    /// Opens the active log at `path` for appending, cutting off any torn tail record
+    /// so new records are never written after garbage.
+    pub fn open(
+        path: &Path,
+        fsync: FsyncPolicy,
+        next_sequence: u64,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut len = 0;
+        if path.exists() {
+            len = read_file(path)?.len;
+            let file = OpenOptions::new().write(true).open(path)?;
+            if file.metadata()?.len() != len {
+                file.set_len(len)?;
+                file.sync_all()?;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open mutation log {:?}: {}", path, e))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            len,
+            next_sequence,
+            fsync,
+            failed: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the last appended record, or the one before `next_sequence`.
+    pub fn last_sequence(&self) -> u64 {
+        self.next_sequence - 1
+    }
+
+    /// Appends `record` and returns its sequence number. A failed append leaves no
+    /// trace of the record, so later ones are still readable.
+    pub fn append(&mut self, record: &WalRecord) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        if self.failed {
+            return Err(format!(
+                "Mutation log {:?} is unusable after an earlier write failed",
+                self.path
+            )
+            .into());
+        }
+        let sequence = self.next_sequence;
+        let payload = encode_record(sequence, record)?;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        if let Err(e) = self.write_frame(&frame) {
+            if let Err(cleanup) = self.discard_partial_frame() {
+                log::error!(
+                    "Could not cut a failed write off mutation log {:?}, refusing further appends: {}",
+                    self.path,
+                    cleanup
+                );
+                self.failed = true;
+            }
+            return Err(format!("Failed to append to mutation log {:?}: {}", self.path, e).into());
+        }
+
+        self.len += frame.len() as u64;
+        self.next_sequence += 1;
+        Ok(sequence)
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(frame)?;
+        self.file.flush()?;
+        if self.fsync == FsyncPolicy::Always {
+            self.file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Drops whatever part of a frame is still buffered and truncates the file back
+    /// to the last complete record.
+    fn discard_partial_frame(&mut self) -> std::io::Result<()> {
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        file.set_len(self.len)?;
+        file.sync_all()?;
+        // into_parts hands back the buffer instead of flushing it on drop
+        let (_, _unwritten) = std::mem::replace(&mut self.file, BufWriter::new(file)).into_parts();
+        Ok(())
+    }
+
+    /// Moves the active file aside as a segment and starts a new one.
+    ///
+    /// Returns the last sequence number written, every record up to which now lives
+    /// in a rotated segment.
+    pub fn rotate(&mut self) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        self.file.flush()?;
+        let last_sequence = self.last_sequence();
+        // An empty active file has nothing to hand over, and renaming it could
+        // clobber a segment of the same name that is still waiting for compaction
+        if self.file.get_ref().metadata()?.len() == 0 {
+            return Ok(last_sequence);
+        }
+        self.file.get_ref().sync_all()?;
+
+        fs::rename(&self.path, segment_path(&self.path, last_sequence))
+            .map_err(|e| format!("Failed to rotate mutation log {:?}: {}", self.path, e))?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Failed to open mutation log {:?}: {}", self.path, e))?;
+        self.file = BufWriter::new(file);
+        self.len = 0;
+
+        Ok(last_sequence)
+    }
+}
+
+/// Reads the rotated segments and the active log at `path`, in sequence order.
+///
+/// A torn or corrupted record ends the file it is in: it and anything after it are
+/// skipped with a warning, since nothing past it was ever acknowledged.
+pub fn read_log(path: &Path) -> Result<LogContents, Box<dyn Error + Send + Sync>> {
+    let mut files: Vec<PathBuf> = segments(path)?
+        .into_iter()
+        .map(|(_, segment)| segment)
+        .collect();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+
+    let mut contents = LogContents::default();
+    for file in files {
+        for (sequence, record) in read_file(&file)?.records {
+            if sequence <= contents.last_sequence {
+                log::warn!(
+                    "Skipping out of order record {} in mutation log {:?}",
+                    sequence,
+                    file
+                );
+                continue;
+            }
+            contents.last_sequence = sequence;
+            contents.records.push((sequence, record));
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Deletes rotated segments whose records are all at or below `sequence`.
+pub fn remove_segments_through(
+    path: &Path,
+    sequence: u64,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut removed = 0;
+    for (last_sequence, segment) in segments(path)? {
+        if last_sequence <= sequence {
+            fs::remove_file(&segment)
+                .map_err(|e| format!("Failed to remove log segment {:?}: {}", segment, e))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn segment_path(path: &Path, last_sequence: u64) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{:020}", last_sequence));
+    path.with_file_name(file_name)
+}
+
+/// Rotated segments of the log at `path`, sorted by their last sequence number.
+fn segments(path: &Path) -> Result<Vec<(u64, PathBuf)>, Box<dyn Error + Send + Sync>> {
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let dir = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(sequence) = name
+            .strip_prefix(&prefix)
+            .and_then(|suffix| suffix.parse::<u64>().ok())
+        {
+            segments.push((sequence, entry.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// The records of one log file up to the first torn or corrupted one.
+struct IntactPrefix {
+    records: Vec<(u64, WalRecord)>,
+    /// Byte length of the intact records.
+    len: u64,
+}
+
+/// Reads all intact records of one log file.
+fn read_file(path: &Path) -> Result<IntactPrefix, Box<dyn Error + Send + Sync>> {
+    let data =
+        fs::read(path).map_err(|e| format!("Failed to read mutation log {:?}: {}", path, e))?;
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (sequence, record, frame_len) = match decode_frame(&data[pos..]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::warn!(
+                    "Skipping corrupted tail of mutation log {:?} at byte {} ({} bytes): {}",
+                    path,
+                    pos,
+                    data.len() - pos,
+                    e
+                );
+                break;
+            }
+        };
+        records.push((sequence, record));
+        pos += frame_len;
+    }
+
+    Ok(IntactPrefix {
+        records,
+        len: pos as u64,
+    })
+}
+
+/// Decodes the frame at the start of `data`, returning the record and the frame length.
+fn decode_frame(data: &[u8]) -> Result<(u64, WalRecord, usize), Box<dyn Error + Send + Sync>> {
+    if data.len() < FRAME_HEADER_LEN {
+        return Err("truncated frame header".into());
+    }
+    let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let payload = data
+        .get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len)
+        .ok_or("truncated record")?;
+    if crc32fast::hash(payload) != crc {
+        return Err("checksum mismatch".into());
+    }
+
+    let (sequence, record) = decode_record(payload)?;
+    Ok((sequence, record, FRAME_HEADER_LEN + len))
+}
+
+fn encode_record(
+    sequence: u64,
+    record: &WalRecord,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut payload = Vec::with_capacity(64);
+    payload.extend_from_slice(&sequence.to_le_bytes());
+
+    let push_id = |payload: &mut Vec<u8>, video_id: &str| {
+        let id_len = u32::try_from(video_id.len())
+            .map_err(|_| format!("video_id too long to log: {} bytes", video_id.len()))?;
+        payload.extend_from_slice(&id_len.to_le_bytes());
+        payload.extend_from_slice(video_id.as_bytes());
+        Ok::<(), String>(())
+    };
+
+    match record {
+        WalRecord::Insert { video_id, hash } => {
+            payload.push(RECORD_INSERT);
+            push_id(&mut payload, video_id)?;
+            payload.extend_from_slice(&hash.to_le_bytes());
+        }
+        WalRecord::Remove { video_id } => {
+            payload.push(RECORD_REMOVE);
+            push_id(&mut payload, video_id)?;
+        }
+        WalRecord::Rebuild => payload.push(RECORD_REBUILD),
+    }
+
+    Ok(payload)
+}
+
+fn decode_record(payload: &[u8]) -> Result<(u64, WalRecord), Box<dyn Error + Send + Sync>> {
+    let mut pos = 0;
+
+    let sequence = u64::from_le_bytes(take(payload, &mut pos, 8)?.try_into().unwrap());
+    let kind = take(payload, &mut pos, 1)?[0];
+    let record = match kind {
+        RECORD_INSERT | RECORD_REMOVE => {
+            let id_len = u32::from_le_bytes(take(payload, &mut pos, 4)?.try_into().unwrap());
+            let video_id = String::from_utf8(take(payload, &mut pos, id_len as usize)?.to_vec())
+                .map_err(|e| format!("invalid video_id: {}", e))?;
+            if kind == RECORD_INSERT {
+                let hash = u64::from_le_bytes(take(payload, &mut pos, 8)?.try_into().unwrap());
+                WalRecord::Insert { video_id, hash }
+            } else {
+                WalRecord::Remove { video_id }
+            }
+        }
+        RECORD_REBUILD => WalRecord::Rebuild,
+        other => return Err(format!("unknown record type {}", other).into()),
+    };
+
+    Ok((sequence, record))
+}
+
+fn take<'a>(
+    payload: &'a [u8],
+    pos: &mut usize,
+    len: usize,
+) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+    let bytes = payload
+        .get(*pos..*pos + len)
+        .ok_or("record payload too short")?;
+    *pos += len;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("videohash-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("index.wal")
+    }
+
+    fn insert(video_id: &str, hash: u64) -> WalRecord {
+        WalRecord::Insert {
+            video_id: video_id.to_string(),
+            hash,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read_back() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_log("round-trip");
+        let records = vec![
+            insert("video-001", 42),
+            WalRecord::Remove {
+                video_id: "video-001".to_string(),
+            },
+            WalRecord::Rebuild,
+        ];
+
+        let mut log = MutationLog::open(&path, FsyncPolicy::Always, 1)?;
+        for record in records.iter() {
+            log.append(record)?;
+        }
+
+        let contents = read_log(&path)?;
+        assert_eq!(contents.last_sequence, 3);
+        assert_eq!(
+            contents.records,
+            records
+                .into_iter()
+                .enumerate()
+                .map(|(i, record)| (i as u64 + 1, record))
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_tail_is_skipped_and_truncated(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_log("torn-tail");
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, 1)?;
+        log.append(&insert("video-001", 1))?;
+        log.append(&insert("video-002", 2))?;
+        drop(log);
+
+        // Simulate a crash half way through writing the second record
+        let data = fs::read(&path)?;
+        fs::write(&path, &data[..data.len() - 3])?;
+        assert_eq!(read_log(&path)?.records, vec![(1, insert("video-001", 1))]);
+
+        // Reopening cuts the torn record off so new appends stay readable
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, 2)?;
+        log.append(&insert("video-003", 3))?;
+        assert_eq!(
+            read_log(&path)?.records,
+            vec![(1, insert("video-001", 1)), (2, insert("video-003", 3))]
+        );
+
+        // A flipped bit is caught by the checksum
+        let mut data = fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        fs::write(&path, &data)?;
+        assert_eq!(read_log(&path)?.records, vec![(1, insert("video-001", 1))]);
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_append_leaves_no_partial_record(
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_log("failed-append");
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, 1)?;
+        log.append(&insert("video-001", 1))?;
+
+        // Half a frame that reached the file, and half a frame still buffered, as
+        // a write failing part way leaves them
+        log.file.write_all(&[0x2a; 5])?;
+        log.file.flush()?;
+        log.file.write_all(&[0x2a; 3])?;
+        log.discard_partial_frame()?;
+
+        log.append(&insert("video-002", 2))?;
+        drop(log);
+        assert_eq!(
+            read_log(&path)?.records,
+            vec![(1, insert("video-001", 1)), (2, insert("video-002", 2))]
+        );
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_and_compact() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = temp_log("rotate");
+        let mut log = MutationLog::open(&path, FsyncPolicy::Never, 1)?;
+        log.append(&insert("video-001", 1))?;
+        log.append(&insert("video-002", 2))?;
+        assert_eq!(log.rotate()?, 2);
+        log.append(&insert("video-003", 3))?;
+
+        assert_eq!(read_log(&path)?.records.len(), 3);
+
+        assert_eq!(remove_segments_through(&path, 2)?, 1);
+        assert_eq!(read_log(&path)?.records, vec![(3, insert("video-003", 3))]);
+
+        fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use google_cloud_token::TokenSource;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::bigquery_api::{self, DEFAULT_API_URL};
+use crate::config::Secret;
+use crate::retry::{self, SendError};
+
+/// Streaming inserts only need this scope.
+const INSERT_SCOPES: [&str; 1] = ["https://www.googleapis.com/auth/bigquery.insertdata"];
+
+/// BigQuery rejects insertAll requests with more rows than this.
+pub(crate) const MAX_INSERT_ROWS: usize = 50_000;
+
+/// A table written to through the BigQuery REST API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableRef {
+    pub api_url: String,
+    pub project_id: String,
+    pub dataset: String,
+    pub table: String,
+}
+
+impl TableRef {
+    /// Parses the `project.dataset.table` or `dataset.table` named by `key`, taking
+    /// the project from `BIGQUERY_PROJECT`/`GOOGLE_CLOUD_PROJECT` when it is left out
+    /// and the endpoint from `BIGQUERY_API_URL`. Returns `None` when `key` is unset.
+    pub(crate) fn from_lookup(
+        key: &str,
+        lookup: &impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let value = match lookup(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let default_project =
+            || lookup("BIGQUERY_PROJECT").or_else(|| lookup("GOOGLE_CLOUD_PROJECT"));
+        let parts: Vec<&str> = value.split('.').collect();
+        let (project_id, dataset, table) = match parts.as_slice() {
+            [project, dataset, table] => (Some(project.to_string()), *dataset, *table),
+            [dataset, table] => (default_project(), *dataset, *table),
+            _ => {
+                return Err(format!(
+                    "Invalid {} '{}', expected project.dataset.table or dataset.table",
+                    key, value
+                )
+                .into())
+            }
+        };
+        let project_id = project_id.ok_or_else(|| {
+            format!(
+                "{} needs a project; set BIGQUERY_PROJECT or GOOGLE_CLOUD_PROJECT",
+                key
+            )
+        })?;
+        if [project_id.as_str(), dataset, table]
+            .iter()
+            .any(|part| part.is_empty() || part.contains('/'))
+        {
+            return Err(format!("Invalid {} '{}'", key, value).into());
+        }
+
+        Ok(Some(Self {
+            api_url: lookup("BIGQUERY_API_URL").unwrap_or_else(|| DEFAULT_API_URL.to_string()),
+            project_id,
+            dataset: dataset.to_string(),
+            table: table.to_string(),
+        }))
+    }
+
+    fn insert_all_url(&self) -> String {
+        format!(
+            "{}/projects/{}/datasets/{}/tables/{}/insertAll",
+            self.api_url.trim_end_matches('/'),
+            self.project_id,
+            self.dataset,
+            self.table
+        )
+    }
+}
+
+impl fmt::Display for TableRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.project_id, self.dataset, self.table)
+    }
+}
+
+/// Where accepted inserts and deletions are written, and how they are batched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteBackConfig {
+    pub table: TableRef,
+    /// Rows sent per `insertAll` request.
+    pub batch_size: usize,
+    /// Longest a change waits in the queue before its batch is sent.
+    pub flush_interval: Duration,
+    /// Attempts per batch, including the first, before its rows are dropped.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled on every further attempt.
+    pub retry_delay: Duration,
+    /// Changes held in memory while BigQuery is slow or down. Further changes are
+    /// dropped with a warning rather than slowing down requests.
+    pub queue_capacity: usize,
+    /// Service account key from `GOOGLE_SA_KEY`, if one was given.
+    pub credentials: Option<Secret>,
+}
+
+impl WriteBackConfig {
+    /// Reads `BIGQUERY_WRITEBACK_TABLE` and friends. Returns `None` when write-back
+    /// is not configured.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, Box<dyn Error + Send + Sync>> {
+        let table = match TableRef::from_lookup("BIGQUERY_WRITEBACK_TABLE", &lookup)? {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+
+        let config = Self {
+            table,
+            batch_size: parse_var(&lookup, "BIGQUERY_WRITEBACK_BATCH_SIZE", 500)?,
+            flush_interval: Duration::from_millis(parse_var(
+                &lookup,
+                "BIGQUERY_WRITEBACK_FLUSH_MS",
+                1000,
+            )?),
+            max_attempts: parse_var(&lookup, "BIGQUERY_WRITEBACK_MAX_ATTEMPTS", 5)?,
+            retry_delay: Duration::from_millis(500),
+            queue_capacity: 100_000,
+            credentials: lookup("GOOGLE_SA_KEY").map(Secret::new),
+        };
+        if config.batch_size == 0 || config.batch_size > MAX_INSERT_ROWS || config.max_attempts == 0
+        {
+            return Err("BIGQUERY_WRITEBACK_BATCH_SIZE must be 1..=50000 and \
+                        BIGQUERY_WRITEBACK_MAX_ATTEMPTS at least 1"
+                .into());
+        }
+        Ok(Some(config))
+    }
+}
+
+/// Parses the variable `key`, or returns `default` when it is unset.
+pub(crate) fn parse_var<T: std::str::FromStr>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    default: T,
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    match lookup(key) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid {} '{}'", key, value).into()),
+        None => Ok(default),
+    }
+}
+
+/// One row of the write-back table. `videohash` is null for deletions.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChangeRow {
+    pub video_id: String,
+    pub videohash: Option<String>,
+    /// `insert` or `delete`.
+    pub operation: &'static str,
+    pub created_at: String,
+}
+
+/// A row with the insert id that lets BigQuery drop it when a retried request had
+/// already landed.
+pub(crate) type Keyed<T> = (String, T);
+
+/// Handle for queueing changes; cheap to clone. The background writer stops once
+/// every handle is dropped and the queue is drained.
+#[derive(Clone)]
+pub struct WriteBack {
+    sender: mpsc::Sender<Keyed<ChangeRow>>,
+}
+
+impl WriteBack {
+    /// Starts the background writer, authenticating every request with `auth`.
+    pub fn start(
+        config: WriteBackConfig,
+        auth: Arc<dyn TokenSource>,
+    ) -> (Self, tokio::task::JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel(config.queue_capacity);
+        let client =
+            InsertAllClient::new(&config.table, auth, config.max_attempts, config.retry_delay);
+        let worker = tokio::spawn(async move {
+            while let Some(batch) =
+                next_batch(&mut receiver, config.batch_size, config.flush_interval).await
+            {
+                client.insert(batch).await;
+            }
+        });
+        (Self { sender }, worker)
+    }
+
+    pub fn record_added(&self, video_id: &str, hash: &str) {
+        self.enqueue(video_id, Some(hash), "insert");
+    }
+
+    pub fn record_removed(&self, video_id: &str) {
+        self.enqueue(video_id, None, "delete");
+    }
+
+    fn enqueue(&self, video_id: &str, hash: Option<&str>, operation: &'static str) {
+        let now = Utc::now();
+        let insert_id = format!(
+            "{}:{}:{}",
+            operation,
+            video_id,
+            now.timestamp_nanos_opt().unwrap_or_default()
+        );
+        let row = ChangeRow {
+            video_id: video_id.to_string(),
+            videohash: hash.map(str::to_string),
+            operation,
+            created_at: now.to_rfc3339_opts(SecondsFormat::Micros, true),
+        };
+        if let Err(e) = self.sender.try_send((insert_id, row)) {
+            log::warn!(
+                "BigQuery write-back queue unavailable, dropping {} of {}: {}",
+                operation,
+                video_id,
+                e
+            );
+        }
+    }
+}
+
+/// Waits for the next item, then collects more until `batch_size` items or
+/// `flush_interval` after the first. Returns `None` once the channel is closed and
+/// drained.
+pub(crate) async fn next_batch<T>(
+    receiver: &mut mpsc::Receiver<T>,
+    batch_size: usize,
+    flush_interval: Duration,
+) -> Option<Vec<T>> {
+    let first = receiver.recv().await?;
+    let deadline = Instant::now() + flush_interval;
+    let mut batch = vec![first];
+    while batch.len() < batch_size {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Some(item)) => batch.push(item),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+/// Credentials from the same places as the BigQuery reader, limited to inserts.
+pub async fn default_token_source(
+    sa_key: Option<&Secret>,
+) -> Result<Arc<dyn TokenSource>, Box<dyn Error + Send + Sync>> {
+    bigquery_api::token_source(&INSERT_SCOPES, sa_key).await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InsertAllRequest<'a, T> {
+    skip_invalid_rows: bool,
+    rows: Vec<InsertAllRow<'a, T>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InsertAllRow<'a, T> {
+    insert_id: &'a str,
+    json: &'a T,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct InsertAllResponse {
+    #[serde(default)]
+    insert_errors: Vec<InsertError>,
+}
+
+#[derive(Deserialize)]
+struct InsertError {
+    index: usize,
+    #[serde(default)]
+    errors: Vec<ErrorProto>,
+}
+
+#[derive(Deserialize)]
+struct ErrorProto {
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// Streams rows into one table with `insertAll`.
+pub(crate) struct InsertAllClient {
+    client: reqwest::Client,
+    url: String,
+    auth: Arc<dyn TokenSource>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl InsertAllClient {
+    pub(crate) fn new(
+        table: &TableRef,
+        auth: Arc<dyn TokenSource>,
+        max_attempts: u32,
+        retry_delay: Duration,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("Failed to build HTTP client"),
+            url: table.insert_all_url(),
+            auth,
+            max_attempts,
+            retry_delay,
+        }
+    }
+
+    /// Sends `rows`, retrying transport errors, 429s, 5xxs and rows BigQuery did not
+    /// accept for a transient reason. Rows still failing after `max_attempts` are
+    /// logged and dropped; returns how many were.
+    pub(crate) async fn insert<T: Serialize>(&self, mut rows: Vec<Keyed<T>>) -> usize {
+        let mut delay = self.retry_delay;
+        let mut dropped = 0;
+        for attempt in 1..=self.max_attempts {
+            match self.send(&rows).await {
+                Ok(rejected) => {
+                    let mut retry = Vec::new();
+                    for (row, reason) in rows.into_iter().zip(rejected) {
+                        match reason {
+                            None => {}
+                            // "stopped" rows were fine but sent alongside an invalid one
+                            Some((reason, message)) if reason == "invalid" => {
+                                log::error!("BigQuery rejected row {}: {}", row.0, message);
+                                dropped += 1;
+                            }
+                            Some(_) => retry.push(row),
+                        }
+                    }
+                    if retry.is_empty() {
+                        return dropped;
+                    }
+                    rows = retry;
+                }
+                Err(SendError::Fatal(e)) => {
+                    log::error!("Dropping {} rows for {}: {}", rows.len(), self.url, e);
+                    return dropped + rows.len();
+                }
+                Err(SendError::Retryable(e)) => {
+                    log::warn!(
+                        "insertAll attempt {}/{} failed: {}",
+                        attempt,
+                        self.max_attempts,
+                        e
+                    );
+                }
+            }
+            if attempt < self.max_attempts {
+                tokio::time::sleep(retry::jitter(delay)).await;
+                delay = (delay * 2).min(Duration::from_secs(60));
+            }
+        }
+        log::error!(
+            "Dropping {} rows for {} after {} attempts",
+            rows.len(),
+            self.url,
+            self.max_attempts
+        );
+        dropped + rows.len()
+    }
+
+    /// Returns, for every row, the reason and message BigQuery rejected it with, if any.
+    async fn send<T: Serialize>(
+        &self,
+        rows: &[Keyed<T>],
+    ) -> Result<Vec<Option<(String, String)>>, SendError> {
+        let token = self.auth.token().await.map_err(|e| {
+            SendError::Retryable(format!("Failed to get access token: {}", e).into())
+        })?;
+        let request = InsertAllRequest {
+            skip_invalid_rows: false,
+            rows: rows
+                .iter()
+                .map(|(insert_id, json)| InsertAllRow { insert_id, json })
+                .collect(),
+        };
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::AUTHORIZATION, token)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| SendError::Retryable(format!("insertAll request failed: {}", e).into()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let error = format!("insertAll returned {}: {}", status, body).into();
+            return Err(
+                if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    SendError::Retryable(error)
+                } else {
+                    SendError::Fatal(error)
+                },
+            );
+        }
+
+        let body: InsertAllResponse = response.json().await.map_err(|e| {
+            SendError::Retryable(format!("Invalid insertAll response: {}", e).into())
+        })?;
+        let mut rejected = vec![None; rows.len()];
+        for error in body.insert_errors {
+            if let (Some(slot), Some(first)) = (rejected.get_mut(error.index), error.errors.first())
+            {
+                *slot = Some((first.reason.clone(), first.message.clone()));
+            }
+        }
+        Ok(rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(
+        vars: &[(&str, &str)],
+    ) -> Result<Option<WriteBackConfig>, Box<dyn Error + Send + Sync>> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        WriteBackConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_writeback_config() -> Result<(), Box<dyn Error + Send + Sync>> {
+        assert_eq!(config_from(&[])?, None);
+
+        let config = config_from(&[
+            ("GOOGLE_CLOUD_PROJECT", "my-project"),
+            ("BIGQUERY_WRITEBACK_TABLE", "yral_ds.videohash_changes"),
+            ("BIGQUERY_WRITEBACK_BATCH_SIZE", "100"),
+        ])?
+        .unwrap();
+        assert_eq!(config.batch_size, 100);
+        assert_eq!(
+            config.table.insert_all_url(),
+            "https://bigquery.googleapis.com/bigquery/v2/projects/my-project/datasets/yral_ds/tables/videohash_changes/insertAll"
+        );
+
+        assert!(config_from(&[("BIGQUERY_WRITEBACK_TABLE", "yral_ds.changes")]).is_err());
+        assert!(config_from(&[("BIGQUERY_WRITEBACK_TABLE", "changes")]).is_err());
+        assert!(config_from(&[
+            ("BIGQUERY_WRITEBACK_TABLE", "p.d.t"),
+            ("BIGQUERY_WRITEBACK_BATCH_SIZE", "0"),
+        ])
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+// tests/bigquery_source.rs
+
+mod common;
+mod fake_bigquery;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{test, web, App};
+use async_trait::async_trait;
+use common::{admin_request, api_keys};
+use fake_bigquery::{pending_job, results_page, FakeBigQuery};
+use google_cloud_token::TokenSource;
+use videohash_indexer::bigquery::Watermark;
+use videohash_indexer::bigquery_api::RestClient;
+use videohash_indexer::retry::RetryPolicy;
+use videohash_indexer::source::BigQueryReader;
+use videohash_indexer::{configure, create_shared_index, BigQuerySource, VideoHash};
+
+#[derive(Debug)]
+struct StaticToken;
+
+#[async_trait]
+impl TokenSource for StaticToken {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok("Bearer test-token".to_string())
+    }
+}
+
+#[derive(Debug)]
+struct NoToken;
+
+#[async_trait]
+impl TokenSource for NoToken {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Err("metadata server unreachable".into())
+    }
+}
+
+fn reader(fake: &FakeBigQuery, auth: Arc<dyn TokenSource>) -> BigQueryReader {
+    BigQueryReader {
+        source: BigQuerySource {
+            api_url: fake.url.clone(),
+            project_id: Some("test-project".to_string()),
+            ..Default::default()
+        },
+        client: Arc::new(
+            RestClient::new(&fake.url, auth).with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(20),
+            }),
+        ),
+    }
+}
+
+fn index_with_stale_video() -> Arc<videohash_indexer::VideoHashIndex> {
+    let index = create_shared_index();
+    index
+        .add(
+            "stale-video".to_string(),
+            &VideoHash {
+                hash: "1".repeat(64),
+            },
+        )
+        .unwrap();
+    index
+}
+
+#[actix_web::test]
+async fn test_rebuild_pages_through_query_results() {
+    let fake = FakeBigQuery::start().await;
+    let zeros = "0".repeat(64);
+    let mixed = "01".repeat(32);
+    let ones = format!("{}1", "0".repeat(63));
+    fake.respond_with(
+        200,
+        results_page(
+            "job-1",
+            &[
+                vec![Some("video-003"), Some(&zeros), Some("300")],
+                vec![Some("video-002"), Some(&mixed), Some("200")],
+            ],
+            3,
+            Some("page-2"),
+        ),
+    );
+    fake.respond_with(
+        200,
+        results_page(
+            "job-1",
+            &[vec![Some("video-001"), Some(&ones), Some("100")]],
+            3,
+            None,
+        ),
+    );
+
+    let index = index_with_stale_video();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                index.clone(),
+                Arc::new(reader(&fake, Arc::new(StaticToken))),
+            ))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+    let req = admin_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    assert_eq!(index.len(), 3);
+    assert_eq!(
+        index.watermark(),
+        Some(Watermark {
+            created_at_micros: 300,
+            video_id: "video-003".to_string(),
+        })
+    );
+
+    let requests = fake.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(
+        requests[0].path,
+        "/bigquery/v2/projects/test-project/queries"
+    );
+    assert_eq!(
+        requests[0].authorization.as_deref(),
+        Some("Bearer test-token")
+    );
+    assert_eq!(requests[0].body["useLegacySql"], false);
+    assert!(requests[0].body["query"]
+        .as_str()
+        .unwrap()
+        .contains("FROM `hot-or-not-feed-intelligence.yral_ds.video_unique`"));
+    assert_eq!(requests[1].method, "GET");
+    assert_eq!(
+        requests[1].path,
+        "/bigquery/v2/projects/test-project/queries/job-1"
+    );
+    assert!(requests[1].query.contains("pageToken=page-2"));
+    assert!(requests[1].query.contains("location=US"));
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_rebuild_waits_for_running_job() {
+    let fake = FakeBigQuery::start().await;
+    fake.respond_with(200, pending_job("job-2"));
+    fake.respond_with(200, pending_job("job-2"));
+    let zeros = "0".repeat(64);
+    fake.respond_with(
+        200,
+        results_page("job-2", &[vec![Some("video-001"), Some(&zeros)]], 1, None),
+    );
+
+    let index = create_shared_index();
+    let loaded = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap();
+
+    assert_eq!(loaded, 1);
+    let requests = fake.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[1..]
+        .iter()
+        .all(|r| r.path == "/bigquery/v2/projects/test-project/queries/job-2"));
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_malformed_rows_are_skipped() {
+    let fake = FakeBigQuery::start().await;
+    let zeros = "0".repeat(64);
+    fake.respond_with(
+        200,
+        results_page(
+            "job-3",
+            &[
+                vec![Some("video-001"), Some(&zeros)],
+                vec![Some("video-002"), None],
+                vec![Some("video-003"), Some("not a hash")],
+                vec![Some("video-004")],
+                vec![None, Some(&zeros)],
+            ],
+            5,
+            None,
+        ),
+    );
+
+    let index = create_shared_index();
+    let loaded = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap();
+
+    assert_eq!(loaded, 1);
+    assert_eq!(index.len(), 1);
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_failed_loads_keep_the_current_index() {
+    let fake = FakeBigQuery::start().await;
+    let index = index_with_stale_video();
+
+    // Credentials that BigQuery refuses
+    fake.respond_with(
+        401,
+        serde_json::json!({"error": {"code": 401, "message": "Request had invalid authentication credentials."}}),
+    );
+    let error = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("rejected the credentials"), "{}", error);
+
+    // No token at all, however often it is retried; nothing reaches BigQuery
+    let error = index
+        .rebuild_from_source(&reader(&fake, Arc::new(NoToken)))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("metadata server unreachable"), "{}", error);
+    assert_eq!(fake.requests().len(), 1);
+
+    // Fewer rows than the job reported
+    let zeros = "0".repeat(64);
+    fake.respond_with(
+        200,
+        results_page("job-4", &[vec![Some("video-001"), Some(&zeros)]], 2, None),
+    );
+    let error = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("refusing a partial load"), "{}", error);
+
+    assert_eq!(index.len(), 1);
+    assert_eq!(index.watermark(), None);
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_transient_failures_are_retried() {
+    let fake = FakeBigQuery::start().await;
+    let unavailable = serde_json::json!({"error": {"code": 503, "message": "Backend error"}});
+    fake.respond_with(503, unavailable.clone());
+    fake.respond_with(429, unavailable.clone());
+    let zeros = "0".repeat(64);
+    fake.respond_with(
+        200,
+        results_page("job-5", &[vec![Some("video-001"), Some(&zeros)]], 1, None),
+    );
+
+    let index = create_shared_index();
+    let loaded = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap();
+    assert_eq!(loaded, 1);
+
+    // Every attempt carries the same requestId, so BigQuery runs the query once
+    let requests = fake.requests();
+    assert_eq!(requests.len(), 3);
+    let request_id = &requests[0].body["requestId"];
+    assert!(request_id.is_string());
+    assert!(requests.iter().all(|r| &r.body["requestId"] == request_id));
+
+    // Out of attempts
+    for _ in 0..3 {
+        fake.respond_with(503, unavailable.clone());
+    }
+    let error = index
+        .rebuild_from_source(&reader(&fake, Arc::new(StaticToken)))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("gave up after 3 attempts"), "{}", error);
+    assert_eq!(index.len(), 1);
+
+    fake.stop().await;
+}
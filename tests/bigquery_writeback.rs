@@ -0,0 +1,241 @@
+// tests/bigquery_writeback.rs
+
+mod common;
+mod fake_bigquery;
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{test, web, App};
+use async_trait::async_trait;
+use common::{admin_request, api_keys, search_request};
+use fake_bigquery::FakeBigQuery;
+use google_cloud_token::TokenSource;
+use tokio::sync::Notify;
+use videohash_indexer::bigquery::{LoadSummary, Watermark};
+use videohash_indexer::source::PageHandler;
+use videohash_indexer::writeback::{TableRef, WriteBackConfig};
+use videohash_indexer::{
+    configure, create_shared_index, BigQuerySource, HashSource, SearchRequest, VideoHash, WriteBack,
+};
+
+#[derive(Debug)]
+struct StaticToken;
+
+#[async_trait]
+impl TokenSource for StaticToken {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok("Bearer test-token".to_string())
+    }
+}
+
+/// Holds its one row back until released, so a test can act mid-rebuild.
+struct GatedSource {
+    video_id: &'static str,
+    started: Notify,
+    release: Notify,
+}
+
+#[async_trait]
+impl HashSource for GatedSource {
+    fn name(&self) -> String {
+        "gated source".to_string()
+    }
+
+    async fn load(
+        &self,
+        _since: Option<&Watermark>,
+        on_page: &mut PageHandler<'_>,
+    ) -> Result<LoadSummary, Box<dyn Error + Send + Sync>> {
+        self.started.notify_one();
+        self.release.notified().await;
+        let hash = VideoHash {
+            hash: "1".repeat(64),
+        };
+        on_page(vec![(self.video_id.to_string(), hash)])?;
+        Ok(LoadSummary {
+            loaded: 1,
+            watermark: None,
+        })
+    }
+}
+
+fn config(fake: &FakeBigQuery) -> WriteBackConfig {
+    WriteBackConfig {
+        table: TableRef {
+            api_url: fake.url.clone(),
+            project_id: "test-project".to_string(),
+            dataset: "test_ds".to_string(),
+            table: "videohash_changes".to_string(),
+        },
+        batch_size: 10,
+        flush_interval: Duration::from_millis(50),
+        max_attempts: 3,
+        retry_delay: Duration::from_millis(10),
+        queue_capacity: 100,
+        credentials: None,
+    }
+}
+
+#[actix_web::test]
+async fn test_inserts_and_deletions_are_written_back() {
+    let fake = FakeBigQuery::start().await;
+    let (write_back, worker) = WriteBack::start(config(&fake), Arc::new(StaticToken));
+
+    let shared_index = create_shared_index();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(write_back.clone()))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let req = search_request(test::TestRequest::post())
+        .uri("/search")
+        .set_json(&SearchRequest {
+            video_id: "video-001".to_string(),
+            hash: "0".repeat(64),
+            insert: true,
+            max_distance: None,
+            return_all: false,
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = admin_request(test::TestRequest::delete())
+        .uri("/hash/video-001")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Dropping every handle makes the writer flush what it has and stop
+    drop(app);
+    drop(write_back);
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let requests = fake.requests();
+    let rows: Vec<&serde_json::Value> = requests
+        .iter()
+        .flat_map(|request| request.body["rows"].as_array().unwrap())
+        .collect();
+    assert_eq!(
+        requests[0].path,
+        "/bigquery/v2/projects/test-project/datasets/test_ds/tables/videohash_changes/insertAll"
+    );
+    assert_eq!(
+        requests[0].authorization.as_deref(),
+        Some("Bearer test-token")
+    );
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["json"]["video_id"], "video-001");
+    assert_eq!(rows[0]["json"]["videohash"], "0".repeat(64));
+    assert_eq!(rows[0]["json"]["operation"], "insert");
+    assert_eq!(rows[1]["json"]["operation"], "delete");
+    assert!(rows[1]["json"]["videohash"].is_null());
+    assert_ne!(rows[0]["insertId"], rows[1]["insertId"]);
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_deletes_during_a_rebuild_are_kept_and_written_back() {
+    let fake = FakeBigQuery::start().await;
+    let (write_back, worker) = WriteBack::start(config(&fake), Arc::new(StaticToken));
+    let source = Arc::new(GatedSource {
+        video_id: "video-incoming",
+        started: Notify::new(),
+        release: Notify::new(),
+    });
+
+    let shared_index = create_shared_index();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(shared_index.clone(), source.clone()))
+            .app_data(web::Data::new(write_back.clone()))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let rebuild = admin_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    // Only the rows being loaded hold this id
+    let delete = async {
+        source.started.notified().await;
+        let req = admin_request(test::TestRequest::delete())
+            .uri("/hash/video-incoming")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        source.release.notify_one();
+        resp
+    };
+    let (rebuilt, deleted) = tokio::join!(test::call_service(&app, rebuild), delete);
+    assert!(rebuilt.status().is_success());
+    assert!(deleted.status().is_success());
+    assert_eq!(shared_index.len(), 0);
+
+    // Responses hold on to the app data, and with it the writer
+    drop((rebuilt, deleted, app));
+    drop(write_back);
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let requests = fake.requests();
+    let rows: Vec<&serde_json::Value> = requests
+        .iter()
+        .flat_map(|request| request.body["rows"].as_array().unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["json"]["video_id"], "video-incoming");
+    assert_eq!(rows[0]["json"]["operation"], "delete");
+
+    fake.stop().await;
+}
+
+#[actix_web::test]
+async fn test_failed_rows_are_retried() {
+    let fake = FakeBigQuery::start().await;
+    fake.respond_with(
+        503,
+        serde_json::json!({"error": {"message": "unavailable"}}),
+    );
+    fake.respond_with(
+        200,
+        serde_json::json!({
+            "insertErrors": [
+                {"index": 1, "errors": [{"reason": "backendError", "message": "try again"}]},
+                {"index": 2, "errors": [{"reason": "invalid", "message": "bad row"}]}
+            ]
+        }),
+    );
+    let (write_back, worker) = WriteBack::start(config(&fake), Arc::new(StaticToken));
+
+    write_back.record_added("video-001", &"0".repeat(64));
+    write_back.record_added("video-002", &"1".repeat(64));
+    write_back.record_removed("video-003");
+    drop(write_back);
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // 503, then a partial failure, then only the transiently failed row again
+    let requests = fake.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].body, requests[1].body);
+    let retried = requests[2].body["rows"].as_array().unwrap();
+    assert_eq!(retried.len(), 1);
+    assert_eq!(retried[0], requests[1].body["rows"][1]);
+    assert_eq!(retried[0]["json"]["video_id"], "video-002");
+
+    fake.stop().await;
+}
@@ -0,0 +1,33 @@
+// API keys shared by the test crates, and requests that carry them.
+#![allow(dead_code)]
+
+use actix_web::test::TestRequest;
+use videohash_indexer::ApiKeys;
+
+pub const SEARCH_KEY: &str = "search-key-0123456789";
+pub const ADMIN_KEY: &str = "admin-key-0123456789";
+
+/// `upload-pipeline` may only search and `ops` may only administer, so each
+/// key is refused the other's endpoints.
+pub fn api_keys() -> ApiKeys {
+    ApiKeys::from_json(&format!(
+        r#"[
+            {{"name": "upload-pipeline", "key": "{}", "scopes": ["search"]}},
+            {{"name": "ops", "key": "{}", "scopes": ["admin"]}}
+        ]"#,
+        SEARCH_KEY, ADMIN_KEY
+    ))
+    .unwrap()
+}
+
+pub fn with_key(req: TestRequest, key: &str) -> TestRequest {
+    req.insert_header(("Authorization", format!("Bearer {}", key)))
+}
+
+pub fn search_request(req: TestRequest) -> TestRequest {
+    with_key(req, SEARCH_KEY)
+}
+
+pub fn admin_request(req: TestRequest) -> TestRequest {
+    with_key(req, ADMIN_KEY)
+}
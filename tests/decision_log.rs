@@ -0,0 +1,110 @@
+// tests/decision_log.rs
+
+mod fake_bigquery;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{test, web, App};
+use async_trait::async_trait;
+use fake_bigquery::FakeBigQuery;
+use google_cloud_token::TokenSource;
+use videohash_indexer::decisions::{BigQueryDecisionSink, DecisionLogConfig};
+use videohash_indexer::writeback::TableRef;
+use videohash_indexer::{
+    configure, create_shared_index, BigQuerySource, DecisionLog, SearchRequest, VideoHash,
+};
+
+#[derive(Debug)]
+struct StaticToken;
+
+#[async_trait]
+impl TokenSource for StaticToken {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok("Bearer test-token".to_string())
+    }
+}
+
+#[actix_web::test]
+async fn test_duplicate_decisions_reach_bigquery() {
+    let fake = FakeBigQuery::start().await;
+    let sink = BigQueryDecisionSink::new(
+        TableRef {
+            api_url: fake.url.clone(),
+            project_id: "test-project".to_string(),
+            dataset: "analytics".to_string(),
+            table: "duplicate_decisions".to_string(),
+        },
+        Arc::new(StaticToken),
+    );
+    let (decision_log, worker) = DecisionLog::start(
+        Arc::new(sink),
+        DecisionLogConfig {
+            buffer_size: 100,
+            batch_size: 10,
+            flush_interval: Duration::from_millis(50),
+        },
+    );
+
+    let shared_index = create_shared_index();
+    shared_index
+        .add(
+            "original-video".to_string(),
+            &VideoHash {
+                hash: "0".repeat(64),
+            },
+        )
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(decision_log.clone())),
+    )
+    .await;
+
+    // One duplicate and one new video; only the duplicate is a decision worth recording
+    for (video_id, hash) in [
+        ("reupload", format!("{}1", "0".repeat(63))),
+        ("new-video", "1".repeat(64)),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: video_id.to_string(),
+                hash,
+                insert: true,
+                max_distance: Some(3),
+                return_all: false,
+            })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    drop(app);
+    drop(decision_log);
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let requests = fake.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].path,
+        "/bigquery/v2/projects/test-project/datasets/analytics/tables/duplicate_decisions/insertAll"
+    );
+    let rows = requests[0].body["rows"].as_array().unwrap();
+    assert_eq!(rows.len(), 1);
+    let event = &rows[0]["json"];
+    assert_eq!(event["video_id"], "reupload");
+    assert_eq!(event["matched_video_id"], "original-video");
+    assert_eq!(event["hamming_distance"], 1);
+    assert_eq!(event["threshold"], 3);
+    assert_eq!(event["read_only"], false);
+    assert!(event["decided_at"].as_str().unwrap().ends_with('Z'));
+
+    fake.stop().await;
+}
@@ -0,0 +1,135 @@
+// A local stand-in for the BigQuery REST API.
+//
+// Records every request it receives and answers from a script of canned
+// responses, falling back to an empty success once the script runs out.
+// Each test crate uses a different part of it.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub authorization: Option<String>,
+    pub body: serde_json::Value,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<RecordedRequest>,
+    responses: VecDeque<(u16, serde_json::Value)>,
+}
+
+pub struct FakeBigQuery {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+    server: actix_web::dev::ServerHandle,
+}
+
+impl FakeBigQuery {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let app_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .default_service(web::to(handle))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/bigquery/v2", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        Self {
+            url,
+            state,
+            server: handle,
+        }
+    }
+
+    /// Queues a response for the next request that has not been answered yet.
+    pub fn respond_with(&self, status: u16, body: serde_json::Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .push_back((status, body));
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub async fn stop(self) {
+        self.server.stop(true).await;
+    }
+}
+
+async fn handle(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<Arc<Mutex<State>>>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.requests.push(RecordedRequest {
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        query: req.query_string().to_string(),
+        authorization: req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body: serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+    });
+
+    let (status, body) = state
+        .responses
+        .pop_front()
+        .unwrap_or((200, serde_json::json!({})));
+    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).json(body)
+}
+
+/// A completed `jobs.query`/`getQueryResults` response holding `rows`, each a list
+/// of cells where `None` is a SQL null.
+pub fn results_page(
+    job_id: &str,
+    rows: &[Vec<Option<&str>>],
+    total_rows: usize,
+    page_token: Option<&str>,
+) -> serde_json::Value {
+    let rows: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let cells: Vec<serde_json::Value> =
+                row.iter().map(|v| serde_json::json!({ "v": v })).collect();
+            serde_json::json!({ "f": cells })
+        })
+        .collect();
+    let mut page = serde_json::json!({
+        "jobReference": {"projectId": "test-project", "jobId": job_id, "location": "US"},
+        "jobComplete": true,
+        "totalRows": total_rows.to_string(),
+        "rows": rows,
+    });
+    if let Some(token) = page_token {
+        page["pageToken"] = token.into();
+    }
+    page
+}
+
+/// A response for a query job that is still running.
+pub fn pending_job(job_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "jobReference": {"projectId": "test-project", "jobId": job_id, "location": "US"},
+        "jobComplete": false,
+    })
+}
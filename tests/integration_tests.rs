@@ -1,18 +1,25 @@
 // tests/integration_tests.rs
 
+mod common;
+
 use actix_web::{test, web, App};
+use common::{admin_request, api_keys, search_request, with_key, ADMIN_KEY, SEARCH_KEY};
 use std::sync::Arc;
-use videohash_indexer::{create_shared_index, delete_hash, search, SearchRequest, VideoHashIndex};
+use videohash_indexer::limits::RateLimit;
+use videohash_indexer::source::JsonlSource;
+use videohash_indexer::{
+    configure, create_shared_index, BigQuerySource, NeighborsRequest, RateLimiter, RequireReady,
+    SearchPolicy, SearchPolicyStore, SearchRequest,
+};
 
 #[actix_web::test]
 async fn test_search_add_new_hash() {
     let shared_index = create_shared_index();
 
-    let app = test::init_service(
-        App::new()
-            .app_data(web::Data::new(shared_index.clone()))
-            .route("/search", web::post().to(search)),
-    )
+    let app = test::init_service(App::new().configure(configure(
+        shared_index.clone(),
+        Arc::new(BigQuerySource::default()),
+    )))
     .await;
 
     let req = test::TestRequest::post()
@@ -20,6 +27,9 @@ async fn test_search_add_new_hash() {
         .set_json(&SearchRequest {
             video_id: "test-video-1".to_string(),
             hash: "0".repeat(64),
+            insert: true,
+            max_distance: None,
+            return_all: false,
         })
         .to_request();
 
@@ -33,6 +43,39 @@ async fn test_search_add_new_hash() {
     assert_eq!(response["hash_added"], true);
 }
 
+#[actix_web::test]
+async fn test_read_only_search_does_not_insert() {
+    let shared_index = create_shared_index();
+
+    let app = test::init_service(App::new().configure(configure(
+        shared_index.clone(),
+        Arc::new(BigQuerySource::default()),
+    )))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/search")
+        .set_json(&SearchRequest {
+            video_id: "test-video-1".to_string(),
+            hash: "0".repeat(64),
+            insert: false,
+            max_distance: None,
+            return_all: false,
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body = test::read_body(resp).await;
+    let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(response["match_found"], false);
+    assert_eq!(response["hash_added"], false);
+    assert_eq!(response["would_add"], true);
+    assert!(shared_index.is_empty());
+}
+
 #[actix_web::test]
 async fn test_search_find_similar_hash() {
     let shared_index = create_shared_index();
@@ -47,11 +90,10 @@ async fn test_search_find_similar_hash() {
         )
         .unwrap();
 
-    let app = test::init_service(
-        App::new()
-            .app_data(web::Data::new(shared_index.clone()))
-            .route("/search", web::post().to(search)),
-    )
+    let app = test::init_service(App::new().configure(configure(
+        shared_index.clone(),
+        Arc::new(BigQuerySource::default()),
+    )))
     .await;
 
     // Search with a slightly different hash (5 bits different)
@@ -60,6 +102,9 @@ async fn test_search_find_similar_hash() {
         .set_json(&SearchRequest {
             video_id: "test-video-2".to_string(),
             hash: "0".repeat(59) + "11111",
+            insert: true,
+            max_distance: Some(5),
+            return_all: false,
         })
         .to_request();
 
@@ -79,6 +124,150 @@ async fn test_search_find_similar_hash() {
     );
 }
 
+#[actix_web::test]
+async fn test_search_return_all_matches() {
+    let shared_index = create_shared_index();
+
+    for (video_id, hash) in [
+        ("test-video-1", "0".repeat(64)),
+        ("test-video-2", "0".repeat(62) + "11"),
+        ("test-video-3", "0".repeat(44) + &"1".repeat(20)),
+    ] {
+        shared_index
+            .add(video_id.to_string(), &videohash_indexer::VideoHash { hash })
+            .unwrap();
+    }
+
+    let app = test::init_service(App::new().configure(configure(
+        shared_index.clone(),
+        Arc::new(BigQuerySource::default()),
+    )))
+    .await;
+
+    // 20 bits away is beyond the server's cap, however large the requested threshold
+    let req = test::TestRequest::post()
+        .uri("/search")
+        .set_json(&SearchRequest {
+            video_id: "test-video-4".to_string(),
+            hash: "0".repeat(63) + "1",
+            insert: false,
+            max_distance: Some(64),
+            return_all: true,
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body = test::read_body(resp).await;
+    let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(response["match_found"], true);
+    assert_eq!(response["match_details"]["video_id"], "test-video-1");
+    let matches = response["matches"].as_array().unwrap();
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches[0]["video_id"], "test-video-1");
+    assert_eq!(matches[0]["hamming_distance"], 1);
+    assert_eq!(matches[1]["video_id"], "test-video-2");
+    assert_eq!(matches[1]["hamming_distance"], 1);
+}
+
+#[actix_web::test]
+async fn test_search_uses_configured_thresholds() {
+    let shared_index = create_shared_index();
+    shared_index
+        .add(
+            "test-video-1".to_string(),
+            &videohash_indexer::VideoHash {
+                hash: "0".repeat(64),
+            },
+        )
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(SearchPolicyStore::new(SearchPolicy {
+                default_max_distance: 3,
+                max_allowed_distance: 4,
+                ..SearchPolicy::default()
+            }))),
+    )
+    .await;
+
+    let search = |hash: String, max_distance: Option<u32>| {
+        test::TestRequest::post()
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: "test-video-2".to_string(),
+                hash,
+                insert: false,
+                max_distance,
+                return_all: false,
+            })
+            .to_request()
+    };
+
+    // Three bits away is a duplicate under the configured default of 3
+    let response: serde_json::Value =
+        test::call_and_read_body_json(&app, search("0".repeat(61) + "111", None)).await;
+    assert_eq!(response["match_found"], true);
+    assert_eq!(response["match_details"]["is_duplicate"], true);
+
+    // Five bits away is beyond the configured cap of 4, whatever the request asks for
+    let response: serde_json::Value =
+        test::call_and_read_body_json(&app, search("0".repeat(59) + "11111", Some(64))).await;
+    assert_eq!(response["match_found"], false);
+}
+
+#[actix_web::test]
+async fn test_neighbors_returns_k_closest() {
+    let shared_index = create_shared_index();
+
+    for (video_id, hash) in [
+        ("test-video-1", "0".repeat(64)),
+        ("test-video-2", "0".repeat(54) + &"1".repeat(10)),
+        ("test-video-3", "1".repeat(64)),
+    ] {
+        shared_index
+            .add(video_id.to_string(), &videohash_indexer::VideoHash { hash })
+            .unwrap();
+    }
+
+    let app = test::init_service(App::new().configure(configure(
+        shared_index.clone(),
+        Arc::new(BigQuerySource::default()),
+    )))
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/neighbors")
+        .set_json(&NeighborsRequest {
+            hash: "0".repeat(60) + "1111",
+            k: 2,
+            max_distance: None,
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body = test::read_body(resp).await;
+    let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    // Nothing is within the duplicate threshold, but the closest videos still come back
+    let neighbors = response["neighbors"].as_array().unwrap();
+    assert_eq!(neighbors.len(), 2);
+    assert_eq!(neighbors[0]["video_id"], "test-video-1");
+    assert_eq!(neighbors[0]["hamming_distance"], 4);
+    assert_eq!(neighbors[0]["is_duplicate"], false);
+    assert_eq!(neighbors[1]["video_id"], "test-video-2");
+    assert_eq!(neighbors[1]["hamming_distance"], 6);
+}
+
 #[actix_web::test]
 async fn test_delete_hash() {
     let shared_index = create_shared_index();
@@ -95,13 +284,16 @@ async fn test_delete_hash() {
 
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(shared_index.clone()))
-            .route("/hash/{video_id}", web::delete().to(delete_hash)),
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(api_keys())),
     )
     .await;
 
     // Delete the hash
-    let req = test::TestRequest::delete()
+    let req = admin_request(test::TestRequest::delete())
         .uri("/hash/test-video-1")
         .to_request();
 
@@ -111,3 +303,535 @@ async fn test_delete_hash() {
     // Verify it's deleted
     assert_eq!(shared_index.len(), 0);
 }
+
+#[actix_web::test]
+async fn test_rebuild_from_jsonl_seed() {
+    let shared_index = create_shared_index();
+    shared_index
+        .add(
+            "stale-video".to_string(),
+            &videohash_indexer::VideoHash {
+                hash: "1".repeat(64),
+            },
+        )
+        .unwrap();
+
+    let seed_path =
+        std::env::temp_dir().join(format!("videohash-seed-{}.jsonl", std::process::id()));
+    std::fs::write(
+        &seed_path,
+        format!(
+            "{{\"video_id\": \"seed-video-1\", \"hash\": \"{}\"}}\n{{\"video_id\": \"seed-video-2\", \"hash\": \"{}\"}}\n",
+            "0".repeat(64),
+            "01".repeat(32)
+        ),
+    )
+    .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(JsonlSource {
+                    path: seed_path.clone(),
+                }),
+            ))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let req = admin_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    // The seed file replaces the index entirely
+    assert_eq!(shared_index.len(), 2);
+    let req = search_request(test::TestRequest::post())
+        .uri("/search")
+        .set_json(&SearchRequest {
+            video_id: "query-video".to_string(),
+            hash: "0".repeat(64),
+            insert: false,
+            max_distance: None,
+            return_all: false,
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let body = test::read_body(resp).await;
+    let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response["match_details"]["video_id"], "seed-video-1");
+
+    std::fs::remove_file(&seed_path).unwrap();
+}
+
+#[actix_web::test]
+async fn test_search_waits_for_index_to_be_ready() {
+    let shared_index = create_shared_index();
+
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(actix_web::web::Data::new(RequireReady)),
+    )
+    .await;
+    let search = || {
+        test::TestRequest::post()
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: "test-video-1".to_string(),
+                hash: "0".repeat(64),
+                insert: true,
+                max_distance: None,
+                return_all: false,
+            })
+            .to_request()
+    };
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 503);
+    let resp = test::call_service(&app, search()).await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "5");
+    assert!(shared_index.is_empty());
+
+    shared_index.mark_ready();
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let resp = test::call_service(&app, search()).await;
+    assert!(resp.status().is_success());
+    assert_eq!(shared_index.len(), 1);
+}
+
+#[actix_web::test]
+async fn test_health_and_status_endpoints() {
+    let shared_index = create_shared_index();
+    let seed_path =
+        std::env::temp_dir().join(format!("videohash-status-{}.jsonl", std::process::id()));
+    std::fs::write(
+        &seed_path,
+        format!(
+            "{{\"video_id\": \"seed-video-1\", \"hash\": \"{}\"}}\n",
+            "0".repeat(64)
+        ),
+    )
+    .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(JsonlSource {
+                    path: seed_path.clone(),
+                }),
+            ))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/healthz").to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = admin_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = admin_request(test::TestRequest::get())
+        .uri("/status")
+        .to_request();
+    let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status["ready"], true);
+    assert_eq!(status["indexed"], 1);
+    assert_eq!(status["mih_built"], true);
+    assert_eq!(status["rebuild_in_progress"], false);
+    assert_eq!(status["last_rebuild"]["rows"], 1);
+    assert!(status["last_rebuild"]["source"]
+        .as_str()
+        .unwrap()
+        .contains("videohash-status"));
+    assert!(status["last_error"].is_null());
+
+    // A failed rebuild is reported but leaves the loaded index serving
+    std::fs::remove_file(&seed_path).unwrap();
+    let req = admin_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    assert!(test::call_service(&app, req)
+        .await
+        .status()
+        .is_server_error());
+
+    let req = admin_request(test::TestRequest::get())
+        .uri("/status")
+        .to_request();
+    let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status["indexed"], 1);
+    assert_eq!(status["last_rebuild"]["rows"], 1);
+    assert!(status["last_error"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("Failed to open hash source"));
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_metrics_endpoint() {
+    let shared_index = create_shared_index();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let search = |video_id: &str| {
+        search_request(test::TestRequest::post())
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: video_id.to_string(),
+                hash: "0".repeat(64),
+                insert: true,
+                max_distance: None,
+                return_all: false,
+            })
+            .to_request()
+    };
+    assert!(test::call_service(&app, search("metrics-video-1"))
+        .await
+        .status()
+        .is_success());
+    assert!(test::call_service(&app, search("metrics-video-2"))
+        .await
+        .status()
+        .is_success());
+    let req = admin_request(test::TestRequest::delete())
+        .uri("/hash/metrics-video-1")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+
+    // Metrics are process-wide, so other tests may have added to the counts
+    assert!(body.contains("videohash_search_outcomes_total{outcome=\"inserted\"}"));
+    assert!(body.contains("videohash_search_outcomes_total{outcome=\"match\"}"));
+    assert!(body.contains("videohash_deletes_total{result=\"deleted\"}"));
+    assert!(body.contains("videohash_match_hamming_distance_bucket{le=\"0\"}"));
+    assert!(body.contains("videohash_http_request_duration_seconds_count{method=\"DELETE\",route=\"/hash/{video_id}\",status=\"200\"}"));
+    assert!(body.contains("videohash_index_size"));
+}
+
+#[actix_web::test]
+async fn test_search_policy_can_be_changed_at_runtime() {
+    let shared_index = create_shared_index();
+    shared_index
+        .add(
+            "test-video-1".to_string(),
+            &videohash_indexer::VideoHash {
+                hash: "0".repeat(64),
+            },
+        )
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(SearchPolicyStore::new(
+                SearchPolicy::default(),
+            )))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let search = |video_id: &str| {
+        search_request(test::TestRequest::post())
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: video_id.to_string(),
+                hash: "0".repeat(61) + "111",
+                insert: true,
+                max_distance: None,
+                return_all: false,
+            })
+            .to_request()
+    };
+    let update = |token: Option<&str>, body: serde_json::Value| {
+        let mut req = test::TestRequest::patch()
+            .uri("/admin/search-policy")
+            .set_json(body);
+        if let Some(token) = token {
+            req = with_key(req, token);
+        }
+        req.to_request()
+    };
+
+    // Three bits away is no duplicate under the default threshold of 1
+    let response: serde_json::Value =
+        test::call_and_read_body_json(&app, search("test-video-2")).await;
+    assert_eq!(response["match_found"], false);
+    assert_eq!(response["hash_added"], true);
+
+    // Changing the policy needs an admin key
+    let resp = test::call_service(
+        &app,
+        update(None, serde_json::json!({"default_max_distance": 3})),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    let resp = test::call_service(
+        &app,
+        update(
+            Some("wrong"),
+            serde_json::json!({"default_max_distance": 3}),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    let resp = test::call_service(
+        &app,
+        update(
+            Some(SEARCH_KEY),
+            serde_json::json!({"default_max_distance": 3}),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+
+    // Invalid policies are refused and leave the current one in place
+    let resp = test::call_service(
+        &app,
+        update(
+            Some(ADMIN_KEY),
+            serde_json::json!({"default_max_distance": 40}),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    let policy: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        update(
+            Some(ADMIN_KEY),
+            serde_json::json!({"default_max_distance": 3, "insert_on_miss": false}),
+        ),
+    )
+    .await;
+    assert_eq!(policy["default_max_distance"], 3);
+    assert_eq!(policy["max_allowed_distance"], 16);
+    assert_eq!(policy["insert_on_miss"], false);
+
+    let response: serde_json::Value =
+        test::call_and_read_body_json(&app, search("test-video-3")).await;
+    assert_eq!(response["match_found"], true);
+
+    // With insert_on_miss off, a miss reports what would have happened instead
+    shared_index.remove("test-video-1").unwrap();
+    shared_index.remove("test-video-2").unwrap();
+    let response: serde_json::Value =
+        test::call_and_read_body_json(&app, search("test-video-4")).await;
+    assert_eq!(response["hash_added"], false);
+    assert_eq!(response["would_add"], true);
+    assert_eq!(shared_index.len(), 0);
+
+    let req = admin_request(test::TestRequest::get())
+        .uri("/admin/search-policy")
+        .to_request();
+    let policy: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(policy["default_max_distance"], 3);
+}
+
+#[actix_web::test]
+async fn test_admin_endpoints_are_off_without_api_keys() {
+    let app = test::init_service(App::new().configure(configure(
+        create_shared_index(),
+        Arc::new(BigQuerySource::default()),
+    )))
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/search-policy")
+        .insert_header(("Authorization", "Bearer anything"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+    for req in [
+        test::TestRequest::post().uri("/rebuild").to_request(),
+        test::TestRequest::delete()
+            .uri("/hash/test-video-1")
+            .to_request(),
+        test::TestRequest::get().uri("/status").to_request(),
+    ] {
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+    }
+}
+
+#[actix_web::test]
+async fn test_api_keys_are_checked_against_scopes() {
+    let shared_index = create_shared_index();
+    shared_index.mark_ready();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(api_keys())),
+    )
+    .await;
+
+    let search = |token: Option<&str>| {
+        let mut req = test::TestRequest::post()
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: "test-video-1".to_string(),
+                hash: "0".repeat(64),
+                insert: true,
+                max_distance: None,
+                return_all: false,
+            });
+        if let Some(token) = token {
+            req = with_key(req, token);
+        }
+        req.to_request()
+    };
+
+    // No key, or an unknown one, is a structured 401
+    let resp = test::call_service(&app, search(None)).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers().get("WWW-Authenticate").unwrap(), "Bearer");
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "Missing or invalid API key");
+    let resp = test::call_service(&app, search(Some("search-key-wrong"))).await;
+    assert_eq!(resp.status(), 401);
+
+    // Search keys search, but a key without the search scope cannot
+    let resp = test::call_service(&app, search(Some(SEARCH_KEY))).await;
+    assert!(resp.status().is_success());
+    let resp = test::call_service(&app, search(Some(ADMIN_KEY))).await;
+    assert_eq!(resp.status(), 403);
+
+    // Deletes and rebuilds need the admin scope
+    let req = search_request(test::TestRequest::delete())
+        .uri("/hash/test-video-1")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(
+        body["error"],
+        "API key 'upload-pipeline' lacks the admin scope"
+    );
+    let req = search_request(test::TestRequest::post())
+        .uri("/rebuild")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+    let req = admin_request(test::TestRequest::delete())
+        .uri("/hash/test-video-1")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert_eq!(shared_index.len(), 0);
+
+    // Status details need an admin key
+    let req = test::TestRequest::get().uri("/status").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = admin_request(test::TestRequest::get())
+        .uri("/status")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Probes and scrapes need no key
+    for uri in ["/healthz", "/readyz", "/metrics"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(
+            resp.status().is_success(),
+            "{} answered {}",
+            uri,
+            resp.status()
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_clients_are_rate_limited_by_key() {
+    let shared_index = create_shared_index();
+    let app = test::init_service(
+        App::new()
+            .configure(configure(
+                shared_index.clone(),
+                Arc::new(BigQuerySource::default()),
+            ))
+            .app_data(web::Data::new(api_keys()))
+            .app_data(web::Data::new(RateLimiter::new(
+                RateLimit {
+                    per_sec: 0.1,
+                    burst: 2,
+                },
+                None,
+            ))),
+    )
+    .await;
+
+    let search = |video_id: &str| {
+        search_request(test::TestRequest::post())
+            .uri("/search")
+            .set_json(&SearchRequest {
+                video_id: video_id.to_string(),
+                hash: "0".repeat(64),
+                insert: true,
+                max_distance: None,
+                return_all: false,
+            })
+            .to_request()
+    };
+
+    assert!(test::call_service(&app, search("test-video-1"))
+        .await
+        .status()
+        .is_success());
+    assert!(test::call_service(&app, search("test-video-2"))
+        .await
+        .status()
+        .is_success());
+    let resp = test::call_service(&app, search("test-video-3")).await;
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers().get("Retry-After").unwrap(), "10");
+    assert_eq!(shared_index.len(), 1);
+
+    // The admin key has a bucket of its own
+    let req = admin_request(test::TestRequest::delete())
+        .uri("/hash/test-video-1")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Refused keys count against the address, so keys cannot be guessed freely
+    let guess = || {
+        with_key(test::TestRequest::post(), "guessed-key-0123456789")
+            .uri("/search")
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, guess()).await.status(), 401);
+    assert_eq!(test::call_service(&app, guess()).await.status(), 401);
+    assert_eq!(test::call_service(&app, guess()).await.status(), 429);
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("videohash_requests_rejected_total{reason=\"rate_limited\"}"));
+}
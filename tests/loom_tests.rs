@@ -0,0 +1,90 @@
+// tests/loom_tests.rs
+//
+// Model-checks concurrent add/remove/search/merge on VideoHashIndex. Run with:
+// RUSTFLAGS="--cfg videohash_loom" cargo test --release --test loom_tests
+
+#![cfg(videohash_loom)]
+
+use loom::sync::Arc;
+use loom::thread;
+use videohash_indexer::{VideoHash, VideoHashIndex};
+
+fn hash(code: u64) -> VideoHash {
+    VideoHash {
+        hash: format!("{:064b}", code),
+    }
+}
+
+fn ids(results: Vec<(String, u32)>) -> Vec<String> {
+    results.into_iter().map(|(video_id, _)| video_id).collect()
+}
+
+#[test]
+fn concurrent_add_remove_search() {
+    loom::model(|| {
+        let index = Arc::new(VideoHashIndex::new());
+        index.add("video-001".to_string(), &hash(0)).unwrap();
+
+        let adder = {
+            let index = index.clone();
+            thread::spawn(move || index.add("video-002".to_string(), &hash(1)).unwrap())
+        };
+        let remover = {
+            let index = index.clone();
+            thread::spawn(move || index.remove("video-001").unwrap())
+        };
+
+        // Every search sees each write either completely or not at all
+        let seen = ids(index.find_within_distance(&hash(0), 1).unwrap());
+        assert!(
+            [
+                vec![],
+                vec!["video-001".to_string()],
+                vec!["video-002".to_string()],
+                vec!["video-001".to_string(), "video-002".to_string()],
+            ]
+            .contains(&seen),
+            "unexpected search result {:?}",
+            seen
+        );
+
+        adder.join().unwrap();
+        assert!(remover.join().unwrap());
+
+        assert_eq!(
+            index.find_within_distance(&hash(0), 1).unwrap(),
+            vec![("video-002".to_string(), 1)]
+        );
+        assert_eq!(index.len(), 1);
+    });
+}
+
+#[test]
+fn merge_keeps_concurrent_writes() {
+    loom::model(|| {
+        let index = Arc::new(VideoHashIndex::new());
+        index.add("video-001".to_string(), &hash(0)).unwrap();
+
+        let merger = {
+            let index = index.clone();
+            thread::spawn(move || index.merge_delta().unwrap())
+        };
+        let writer = {
+            let index = index.clone();
+            thread::spawn(move || {
+                index.add("video-002".to_string(), &hash(1)).unwrap();
+                index.remove("video-001").unwrap()
+            })
+        };
+
+        merger.join().unwrap();
+        assert!(writer.join().unwrap());
+
+        assert_eq!(
+            ids(index.find_within_distance(&hash(0), 1).unwrap()),
+            vec!["video-002"]
+        );
+        assert!(!index.has_exact_match("video-001", &hash(0)).unwrap());
+        assert_eq!(index.len(), 1);
+    });
+}